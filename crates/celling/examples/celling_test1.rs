@@ -22,7 +22,7 @@ const PRESSED_BUTTON: Color = Color::rgb(0.35, 0.75, 0.35);
 const NORMAL_BUTTON: Color = Color::rgb(0.19, 0.28, 0.31);
 
 #[derive(Resource)]
-struct MousePress(CellType);
+struct MousePress(MaterialId);
 
 #[derive(Component)]
 struct Player;
@@ -30,10 +30,9 @@ struct Player;
 fn main() {
     App::new()
         .insert_resource(ClearColor(BACKGROUND_COLOR))
-        .insert_resource(MousePress(CellType::Sand))
+        .insert_resource(MousePress(MaterialId::default()))
         .add_event::<SpawnImageSpriteEvent>()
         .add_event::<RigidizeEvent>()
-        .add_plugins(CellingPlugin::default())
         .add_plugins(RapierPhysicsPlugin::<NoUserData>::pixels_per_meter(100.0))
         .add_plugins(RapierDebugRenderPlugin::default())
         .add_plugins(DefaultPlugins.set(WindowPlugin {
@@ -45,6 +44,10 @@ fn main() {
             ..default()
         }
     ))
+    .add_plugins(CellingPlugin {
+        materials: vec!["materials/default.materials.ron".to_string()],
+        ..default()
+    })
     .add_plugins(bevy_framepace::FramepacePlugin)
     // .add_plugins((LogDiagnosticsPlugin::default(),FrameTimeDiagnosticsPlugin::default()))
     .add_systems(Startup, (setup, handle))
@@ -57,32 +60,22 @@ fn main() {
 
 fn setup(
     mut commands: Commands,
-    mut settings: ResMut<bevy_framepace::FramepaceSettings>
     ) {
     // Camera
     let mut camera = Camera2dBundle::default();
     camera.projection.scaling_mode = ScalingMode::Fixed { width: 1920., height: 1080. };
     commands.spawn(camera);
-
-    use bevy_framepace::Limiter;
-    settings.limiter = Limiter::from_framerate(30.0);
 }
 
-const SAND_COLOR: Color = Color::rgb(0.0, 0.0, 0.0);
-const LIQUIP_COLOR: Color = Color::rgb(0.33, 0.42, 0.71);
-const GAS_COLOR: Color = Color::rgb(220.0/255.0, 221.0/255.0, 213.0/255.0);
-
-fn spwan_wall(cmds: &mut Commands, map: &mut CellsMap, from_x: i32, to_x: i32, from_y: i32, to_y: i32) {
+fn spwan_wall(cmds: &mut Commands, map: &mut CellsMap, reg: &MaterialRegistry, from_x: i32, to_x: i32, from_y: i32, to_y: i32) {
+    let mat = reg.id("stone").unwrap_or_default();
     for i in from_x..=to_x {
         for j in from_y..=to_y {
             let cell_bundle = CellBundle {
-                c: Cell::Stable,
-                d: Density(1),
+                mat,
                 cd: CellDir::None,
             };
-            if let Some(eid) = create_cell(cmds, map, cell_bundle, i * PIXEL_SIZE, j * PIXEL_SIZE, SAND_COLOR) {
-                cmds.entity(eid).insert(Silent);
-            }
+            create_cell(map, reg, cell_bundle, i * PIXEL_SIZE, j * PIXEL_SIZE, None);
         }
     }
 
@@ -119,13 +112,14 @@ fn spwan_wall(cmds: &mut Commands, map: &mut CellsMap, from_x: i32, to_x: i32, f
 pub fn handle(
     mut commands: Commands,
     mut map: ResMut<CellsMap>,
+    reg: Res<MaterialRegistry>,
     asset_server: Res<AssetServer>,
     mut texture_atlases: ResMut<Assets<TextureAtlas>>,
     images: Res<Assets<Image>>,
     mut spawn_events: EventWriter<SpawnImageSpriteEvent>,
 )
 {
-    spwan_wall(&mut commands, &mut map, -1000, 1000, -100, -100);
+    spwan_wall(&mut commands, &mut map, &reg, -1000, 1000, -100, -100);
     // spwan_wall(&mut commands, &mut map, -100, 100, -50, -50);
     // spwan_wall(&mut commands, &mut map, -100, 100, -30, -30);
     // spwan_wall(&mut commands, &mut map, -100, 100, 15, 15);
@@ -159,7 +153,7 @@ pub fn handle(
                 border_color: BorderColor(Color::BLACK),
                 background_color: NORMAL_BUTTON.into(),
                 ..default()
-            }, CellType::Sand))
+            }, reg.id("sand").unwrap_or_default()))
             .with_children(|parent| {
                 parent.spawn(TextBundle::from_section(
                     "SAND",
@@ -186,7 +180,7 @@ pub fn handle(
                 border_color: BorderColor(Color::BLACK),
                 background_color: NORMAL_BUTTON.into(),
                 ..default()
-            }, CellType::Liquip))
+            }, reg.id("water").unwrap_or_default()))
             .with_children(|parent| {
                 parent.spawn(TextBundle::from_section(
                     "LIQUIP",
@@ -213,7 +207,7 @@ pub fn handle(
                 border_color: BorderColor(Color::BLACK),
                 background_color: NORMAL_BUTTON.into(),
                 ..default()
-            }, CellType::Gas))
+            }, reg.id("steam").unwrap_or_default()))
             .with_children(|parent| {
                 parent.spawn(TextBundle::from_section(
                     "GAS",
@@ -224,6 +218,33 @@ pub fn handle(
                     },
                 ));
             });
+    }).with_children(|parent| {
+        parent
+            .spawn((ButtonBundle {
+                style: Style {
+                    width: Val::Px(150.0),
+                    height: Val::Px(65.0),
+                    border: UiRect::all(Val::Px(5.0)),
+                    // horizontally center child text
+                    justify_content: JustifyContent::Center,
+                    // vertically center child text
+                    align_items: AlignItems::Center,
+                    ..default()
+                },
+                border_color: BorderColor(Color::BLACK),
+                background_color: NORMAL_BUTTON.into(),
+                ..default()
+            }, reg.id("fire").unwrap_or_default()))
+            .with_children(|parent| {
+                parent.spawn(TextBundle::from_section(
+                    "FIRE",
+                    TextStyle {
+                        font: asset_server.load("fonts/FiraSans-Bold.ttf"),
+                        font_size: 40.0,
+                        color: Color::rgb(0.9, 0.9, 0.9),
+                    },
+                ));
+            });
     });
 
     /* Create the bouncing ball. */
@@ -247,41 +268,41 @@ pub fn handle(
 }
 
 fn handle_click(
-    mut commands: Commands,
     input: Res<Input<MouseButton>>,
     windows: Query<&Window>,
     mouse_press: ResMut<MousePress>,
     mut map: ResMut<CellsMap>,
+    reg: Res<MaterialRegistry>,
+    sim_rng: Res<SimRng>,
+    tick: Res<SimTick>,
+    mut explode: EventWriter<Explode>,
 ) {
-    let cd = CellDir::new();
+    // 右键在鼠标处爆炸
+    if input.just_pressed(MouseButton::Right) {
+        if let Some(cursor_position) = windows.single().cursor_position() {
+            let x = (cursor_position.x - WINDOW_W / 2.0) as i32;
+            let y = (WINDOW_H / 2.0 - cursor_position.y) as i32;
+            explode.send(Explode {center: Po::create(x, y), radius: 12, power: 8.});
+        }
+    }
     if input.pressed(MouseButton::Left) {
         if let Some(cursor_position) = windows.single().cursor_position() {
-            let ct: CellType = mouse_press.0;
-            let (c, color, d, cd) = match ct {
-                CellType::Sand => {
-                    (Cell::Sand, SAND_COLOR, Density(1), CellDir::None)
-                }
-                CellType::Liquip => {
-                    (Cell::Liquip, LIQUIP_COLOR, Density(1), cd)
-                }
-                CellType::Gas => {
-                    (Cell::Gas, GAS_COLOR, Density(1), cd)
-                }
-                _ => {
-                    (Cell::Stable, GAS_COLOR, Density(1), cd)
-                }
+            let x = (cursor_position.x - WINDOW_W / 2.0) as i32;
+            let y = (WINDOW_H / 2.0 - cursor_position.y) as i32;
+            let cd = CellDir::new(&mut sim_rng.cell_rng(tick.0, &Po::create(x, y)));
+            let mat = mouse_press.0;
+            let cd = match reg.get(mat).class {
+                MoveClass::Liquid | MoveClass::Gas => cd,
+                _ => CellDir::None,
             };
             let cell_bundle = CellBundle {
-                c: c,
-                d: d,
+                mat: mat,
                 cd: cd,
             };
-            let x = (cursor_position.x - WINDOW_W / 2.0) as i32;
-            let y = (WINDOW_H / 2.0 - cursor_position.y) as i32;
             let tmp = get_cell_create_pos(x, y);
             let p = Po {x: tmp.0, y: tmp.1};
-            if map.get(&p) == None {
-                create_cell(&mut commands, &mut map, cell_bundle, x, y, color);
+            if map.get(&p).is_none() {
+                create_cell(&mut map, &reg, cell_bundle, x, y, None);
             }
         }
     }
@@ -293,18 +314,18 @@ fn button_system(
             &Interaction,
             &mut BackgroundColor,
             &Children,
-            &CellType,
+            &MaterialId,
         ),
         (Changed<Interaction>, With<Button>),
     >,
     mut text_query: Query<&mut Text>,
     mut mouse_press: ResMut<MousePress>
 ) {
-    for (interaction, mut color, children, cell_type) in &mut interaction_query {
+    for (interaction, mut color, children, mat) in &mut interaction_query {
         let mut _text = text_query.get_mut(children[0]).unwrap();
         match *interaction {
             Interaction::Pressed => {
-                mouse_press.0 = *cell_type;
+                mouse_press.0 = *mat;
                 *color = PRESSED_BUTTON.into();
             }
             _ => {
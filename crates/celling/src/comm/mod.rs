@@ -9,6 +9,11 @@ pub const PIXEL_SIZE_HALF: i32 = PIXEL_SIZE / 2;
 pub const PIXEL_SIZE_F: f32 = PIXEL_SIZE as f32;
 pub const PIXEL_SIZE_HALF_F: f32 = PIXEL_SIZE_F / 2.;
 
+// 渲染层级：区块贴图 < 刚体碎片 < 调试遮罩
+pub const CHUNK_Z: f32 = 0.;
+pub const DEBRIS_Z: f32 = 1.;
+pub const DEBUG_Z: f32 = 2.;
+
 const NEIGHBOR_TOP_LEFT: Po = Po::new(-1*PIXEL_SIZE, 1*PIXEL_SIZE);
 const NEIGHBOR_TOP: Po = Po::new(0*PIXEL_SIZE, 1*PIXEL_SIZE);
 const NEIGHBOR_TOP_RIGHT: Po = Po::new(1*PIXEL_SIZE, 1*PIXEL_SIZE);
@@ -42,6 +47,29 @@ impl NeighborGetter for Po {
     }
 }
 
+// from到to之间逐格的Bresenham直线，不含起点
+pub fn line_between(from: &Po, to: &Po) -> Vec<Po> {
+    let d = (*to - *from) / PIXEL_SIZE;
+    let (dx, dy) = (d.x.abs(), -d.y.abs());
+    let (sx, sy) = (d.x.signum(), d.y.signum());
+    let mut err = dx + dy;
+    let (mut x, mut y) = (0, 0);
+    let mut line = Vec::with_capacity(dx.max(-dy) as usize);
+    while x != d.x || y != d.y {
+        let e2 = 2 * err;
+        if e2 >= dy {
+            err += dy;
+            x += sx;
+        }
+        if e2 <= dx {
+            err += dx;
+            y += sy;
+        }
+        line.push(*from + Po::new(x, y) * PIXEL_SIZE);
+    }
+    line
+}
+
 pub fn get_fix_pos(n: i32) -> i32 {
     n - n % PIXEL_SIZE
 }
@@ -49,6 +77,11 @@ pub fn get_fix_pos(n: i32) -> i32 {
 pub fn get_cell_create_pos(x: i32, y: i32) ->(i32, i32) {
     (get_fix_pos(x), get_fix_pos(y))
 }
+
+// 离世界坐标pos最近的格子
+pub fn nearest_po(pos: Vec2) -> Po {
+    (pos / PIXEL_SIZE_F).round().as_ivec2() * PIXEL_SIZE
+}
 pub trait PoCreate {
     fn create(x: i32, y: i32) -> Self;
     fn calc_dir_lr(self, to: &Po) -> PoDir;
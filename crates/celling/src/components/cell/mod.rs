@@ -1,54 +1,34 @@
 use bevy::prelude::*;
+use rand::Rng;
 use rand::prelude::SliceRandom;
 use crate::comm::*;
-use crate::prelude::CellsMap;
+use crate::prelude::{CellsMap, Material, MaterialId, MaterialRegistry, RigidMeterial};
 
-#[derive(Component, Eq, PartialEq, Copy, Clone, Default, Debug)]
-pub enum Cell {
-    #[default]
-    Sand,
-    Liquip,
-    Gas,
-    Stable,
-}
-
-#[derive(Component, Default, Clone, Copy)]
-pub enum CellType {
-    #[default]
-    Sand,
-    Liquip,
-    Gas,
-    Stable,
-}
-
-#[derive(Component, Debug)]
+#[derive(Debug, Clone, Copy)]
 pub struct PoInfo {
-    pub e: Entity,
     // 当前帧位置
     pub cp: Po,
     // 上一帧位置
     pub lp: Po,
+    // 移动后的方向
+    pub cd: Option<CellDir>,
+    // 移动后的速度
+    pub v: Option<CellVelocity>,
 }
 
 impl PoInfo {
-    pub fn new(e: Entity, cp: Po, lp: Po) -> Self {
+    pub fn new(cp: Po, lp: Po, cd: Option<CellDir>, v: Option<CellVelocity>) -> Self {
         Self {
-            e, cp, lp
+            cp, lp, cd, v
         }
     }
 }
 
-#[derive(Component, Default)]
+// 单位是格子/帧
+#[derive(Component, Default, Debug, Clone, Copy)]
 pub struct CellVelocity(pub f32, pub f32);
 
-// 静止
-#[derive(Component)]
-pub struct Silent;
-
-#[derive(Component, Default, PartialEq, PartialOrd, Clone, Copy)]
-pub struct Density(pub i32);
-
-#[derive(Component, Default, Clone, Copy)]
+#[derive(Component, Default, Debug, Clone, Copy)]
 // 方向 0=无 1=左 2=右
 pub enum CellDir {
     #[default]
@@ -58,19 +38,16 @@ pub enum CellDir {
 }
 const LIQUIP_CELL_DIR_VEC: [(CellDir, u32); 3] = [(CellDir::None, 1), (CellDir::Left, 10), (CellDir::Right, 10)];
 impl CellDir {
-    pub fn new() -> Self {
-        let mut rng = rand::thread_rng();
-        LIQUIP_CELL_DIR_VEC.choose_weighted(&mut rng, |item| item.1).unwrap().0
+    pub fn new(rng: &mut impl Rng) -> Self {
+        LIQUIP_CELL_DIR_VEC.choose_weighted(rng, |item| item.1).unwrap().0
     }
 
-    pub fn new2(v: [Self; 2]) -> Self {
-        let mut rng = rand::thread_rng();
-        *v.choose(&mut rng).unwrap()
+    pub fn new2(v: [Self; 2], rng: &mut impl Rng) -> Self {
+        *v.choose(rng).unwrap()
     }
 
-    pub fn new3(v: [Self; 3]) -> Self {
-        let mut rng = rand::thread_rng();
-        *v.choose(&mut rng).unwrap()
+    pub fn new3(v: [Self; 3], rng: &mut impl Rng) -> Self {
+        *v.choose(rng).unwrap()
     }
 
     pub fn calc_dir(from: &Po, to: &Po) -> Self {
@@ -84,58 +61,97 @@ impl CellDir {
     }
 }
 
-#[derive(Bundle, Default, Clone)]
+#[derive(Default, Clone, Copy)]
 pub struct CellBundle {
-    pub c: Cell,
-    pub d: Density,
+    pub mat: MaterialId,
     pub cd: CellDir,
 }
 
-pub struct CellBundleQuery {
-    pub t: Transform,
-    pub cb: CellBundle
+// 连续这么多帧没动的格子进入休眠，不再计算
+pub const SLEEP_TICKS: u16 = 30;
+
+// 温度单位是摄氏度，空白处视为环境温度
+pub const AMBIENT_TEMP: f32 = 20.;
+pub const ABSOLUTE_ZERO: f32 = -273.15;
+// 一次温度变化超过这个值才唤醒格子
+pub const HEAT_WAKE: f32 = 0.5;
+
+// 格子数据直接存在CellsMap里，不再是entity
+#[derive(Debug, Clone, Copy)]
+pub struct CellData {
+    pub mat: MaterialId,
+    pub cd: CellDir,
+    pub v: CellVelocity,
+    pub color: [u8; 4],
+    pub rm: Option<RigidMeterial>,
+    // 最后一次移动的帧号，保证跨区块移动后同一帧不会再被更新
+    pub tick: u32,
+    // 连续没有移动的帧数，超过SLEEP_TICKS就休眠
+    pub idle: u16,
+    pub temp: f32,
+    // 变成当前材质后过了多少帧，材质有lifetime时用
+    pub age: u16,
+    // 正在燃烧时剩余的帧数
+    pub burn: Option<u16>,
+    // 液体的压强，单位是液柱的格子数
+    pub pressure: f32,
+    // 压强是从哪个液面格子传过来的
+    pub surface: Option<Po>,
+    // 粉末是否还在滑动，停住的颗粒只往正下方掉
+    pub sliding: bool,
+    // 最后一次参与反应的帧号，跨区块的格子同一帧也只反应一次
+    pub reacted: u32,
 }
 
-impl CellBundleQuery {
-    pub fn new(t: Transform, c: Cell, d: Density, cd: CellDir) -> Self {
-        CellBundleQuery {
-            cb: CellBundle {
-                c: c,
-                d: d,
-                cd: cd
-            },
-            t: t
+impl CellData {
+    pub fn new(bd: CellBundle, color: Color) -> Self {
+        Self {
+            mat: bd.mat,
+            cd: bd.cd,
+            v: CellVelocity(0., 0.),
+            color: color.as_rgba_u8(),
+            rm: None,
+            tick: 0,
+            idle: 0,
+            temp: AMBIENT_TEMP,
+            age: 0,
+            burn: None,
+            pressure: 0.,
+            surface: None,
+            sliding: true,
+            reacted: 0,
         }
     }
+
+    // 按材质新建格子，color为None时从材质的调色板里挑
+    pub fn spawn(m: &Material, p: &Po, cd: CellDir, color: Option<Color>) -> Self {
+        let color = color.unwrap_or_else(|| m.pick_color(p));
+        let mut c = Self::new(CellBundle {mat: m.id, cd}, color);
+        c.temp = m.temperature;
+        c
+    }
+
+    // 原地换成另一种材质，温度不变
+    pub fn switch_material(&mut self, m: &Material, p: &Po) {
+        self.mat = m.id;
+        self.color = m.pick_color(p).as_rgba_u8();
+        self.age = 0;
+        self.burn = None;
+    }
+
+    pub fn is_sleeping(&self) -> bool {
+        self.idle >= SLEEP_TICKS
+    }
+
+    pub fn color(&self) -> Color {
+        Color::rgba_u8(self.color[0], self.color[1], self.color[2], self.color[3])
+    }
 }
 
-pub fn create_cell(cmds: &mut Commands, map: &mut CellsMap, bd: CellBundle, x: i32, y: i32, color: Color) -> Option<Entity> {
+// color为None时从材质的调色板里挑
+pub fn create_cell(map: &mut CellsMap, reg: &MaterialRegistry, bd: CellBundle, x: i32, y: i32, color: Option<Color>) -> Option<Po> {
     let (x, y) = get_cell_create_pos(x, y);
     let p = Po {x, y};
-    // if map.get(&p).is_some() {
-    //     return None;
-    // }
-    let ecmd = cmds.spawn((
-            SpriteBundle {
-                sprite: Sprite {
-                    custom_size: Some(Vec2::splat(PIXEL_SIZE as f32)),
-                    color: color,
-                    ..default()
-                },
-                transform: Transform::from_xyz(
-                    x as f32,
-                    y as f32,
-                    1.,
-                ),
-                ..default()
-            },
-            bd,
-            CellVelocity(0., 0.),
-        )
-    );
-    let e = ecmd.id();
-    // ecmd.insert(PoInfo::new(e, p, p));
-    map.add(&p, &e);
-    // println!("create_cell po={}", &p);
-    Some(e)
+    map.add(&p, CellData::spawn(reg.get(bd.mat), &p, bd.cd, color));
+    Some(p)
 }
@@ -1,6 +1,7 @@
 use bevy::prelude::*;
 
 use crate::comm::*;
+use crate::components::CellData;
 
 #[derive(Component, Debug, Clone, Copy)]
 pub struct RigidCheckField {
@@ -12,9 +13,35 @@ pub struct RigidCheckField {
     pub cur_y: i32
 }
 
-#[derive(Component)]
+#[derive(Component, Debug, Clone, Copy, PartialEq)]
 pub struct RigidMeterial(pub f32);
 
+// 被打飞、转成刚体的格子
+#[derive(Component, Debug, Clone, Copy)]
+pub struct CellDebris {
+    pub cell: CellData,
+    // 连续静止的帧数
+    pub rest: u16,
+}
+
+impl CellDebris {
+    pub fn new(cell: CellData) -> Self {
+        Self {
+            cell,
+            rest: 0,
+        }
+    }
+}
+
+// 一组格子转成的刚体，格子按相对刚体中心的坐标(像素)存着，跟着刚体移动和旋转
+// 停下来以后格子按刚体的Transform放回CellsMap
+#[derive(Component, Debug, Clone)]
+pub struct PixelObject {
+    pub cells: Vec<(Vec2, CellData)>,
+    // 连续静止的帧数
+    pub rest: u16,
+}
+
 impl RigidCheckField {
     pub fn new(w: i32, h: i32) -> Self {
         Self {
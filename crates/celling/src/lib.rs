@@ -7,10 +7,24 @@ mod comm;
 
 use res::*;
 
-pub struct CellingPlugin {}
+pub struct CellingPlugin {
+    // 模拟随机数种子，相同种子和输入下结果可复现
+    pub seed: u64,
+    // 每秒模拟帧数
+    pub tick_rate: f64,
+    // 一个渲染帧里最多追赶的模拟帧数
+    pub max_catch_up: u32,
+    // 额外加载的材质文件(*.materials.ron)，内置材质之外的定义
+    pub materials: Vec<String>,
+}
 impl Default for CellingPlugin {
     fn default() -> Self {
-        Self {}
+        Self {
+            seed: 0,
+            tick_rate: 30.,
+            max_catch_up: 4,
+            materials: Vec::new(),
+        }
     }
 }
 
@@ -18,18 +32,44 @@ impl Plugin for CellingPlugin {
     fn build(&self, app: &mut App) {
         app
         .insert_resource(CellsMap::default())
+        .insert_resource(SimRng::new(self.seed))
+        .insert_resource(SimTime::new(self.tick_rate, self.max_catch_up))
+        .init_resource::<SimTick>()
+        .init_resource::<Particles>()
+        .init_schedule(SimSchedule)
+        .init_asset::<MaterialSet>()
+        .init_asset_loader::<MaterialSetLoader>()
+        .init_resource::<MaterialRegistry>()
+        .init_resource::<MaterialSetHandles>()
+        .add_event::<CellIgnited>()
+        .add_event::<CellReacted>()
+        .add_event::<Explode>()
+        .insert_resource(systems::materials::MaterialPaths(self.materials.clone()))
+        .init_resource::<systems::render::ChunkSprites>()
         .insert_resource(res::settings::Settings::default())
-        .add_systems(Startup, setup)
-        .add_systems(PreUpdate, (systems::rigids::rigidize,))
-        .add_systems(Update, (
+        .add_systems(Startup, (setup, systems::materials::load_materials))
+        .add_systems(SimSchedule, (
+            systems::tick::advance_tick,
             systems::cells::handle,
+            systems::particles::update,
+        ).chain())
+        .add_systems(Update, (
+            systems::materials::register_materials,
+            systems::explode::handle.before(systems::tick::run_sim_schedule),
+            systems::tick::run_sim_schedule,
             systems::rigids::handle,
-            systems::load::spawn_image_sprite_handle,
+            systems::rigids::settle_debris,
+            systems::rigids::settle_pixel_objects,
+            // 图片生成的格子在同一帧里转成刚体，中间不会先跑模拟帧
+            (
+                systems::load::spawn_image_sprite_handle,
+                systems::rigids::rigidize,
+            ).chain().after(systems::tick::run_sim_schedule),
         ))
         .add_systems(PostUpdate, (
-            systems::cells::handle_update_map,
             systems::cells::handle_debug,
-        ));
+            systems::render::render_chunks,
+        ).chain());
     }
 }
 
@@ -0,0 +1,29 @@
+use bevy::prelude::*;
+
+use crate::comm::*;
+use super::material::MaterialId;
+
+// 格子开始燃烧，mat是被点燃的材质
+#[derive(Event, Debug, Clone, Copy)]
+pub struct CellIgnited {
+    pub p: Po,
+    pub mat: MaterialId,
+}
+
+// 在center处爆炸，威力随距离线性衰减到radius格处为0
+// 威力不小于材质hardness的格子被炸掉或变成碎片，剩下的可移动格子往外飞，附近的刚体受到冲量
+#[derive(Event, Debug, Clone, Copy)]
+pub struct Explode {
+    pub center: Po,
+    pub radius: i32,
+    pub power: f32,
+}
+
+// 两个相邻格子按反应表发生了反应，into为None表示消失
+#[derive(Event, Debug, Clone, Copy)]
+pub struct CellReacted {
+    pub p: Po,
+    pub other: Po,
+    pub from: (MaterialId, MaterialId),
+    pub into: (Option<MaterialId>, Option<MaterialId>),
+}
@@ -1,49 +1,433 @@
 use bevy::prelude::*;
-use bevy::utils::HashMap;
+use bevy::utils::{HashMap, HashSet};
 use crate::comm::*;
+use crate::components::{CellData, CellVelocity, ABSOLUTE_ZERO, HEAT_WAKE};
+use super::cell_events::*;
+use super::material::{MaterialRegistry, MoveClass};
+use super::chunk::*;
+use super::chunk_window::*;
 
-#[derive(Resource, Eq, PartialEq, Clone)]
+// CellsMap和ChunkWindow共用的读写接口，规则函数只依赖它
+pub trait CellGrid {
+    fn get(&self, p: &Po) -> Option<&CellData>;
+    fn get_mut(&mut self, p: &Po) -> Option<&mut CellData>;
+    // 只改不影响颜色的字段(压强、燃烧计时、标记等)用，不标脏也不唤醒
+    fn get_mut_quiet(&mut self, p: &Po) -> Option<&mut CellData>;
+    fn swap(&mut self, a: &Po, b: &Po);
+    // 唤醒p和周围8格
+    fn wake(&mut self, p: &Po);
+    // 改变p的温度，变化够大时唤醒周围，p没有格子返回false
+    fn add_heat(&mut self, p: &Po, delta: f32) -> bool;
+    fn add(&mut self, p: &Po, c: CellData) -> Option<CellData>;
+    fn del(&mut self, p: &Po) -> Option<CellData>;
+    // 记下事件，模拟帧结束后统一发送
+    fn record_ignited(&mut self, ev: CellIgnited);
+    fn record_reacted(&mut self, ev: CellReacted);
+    // 记下液体从from的液面挪到to，所有区块更新完后统一执行
+    fn record_flow(&mut self, from: &Po, to: &Po);
+    // 把p处的格子从网格里拿走，变成速度为v(格子/帧)的粒子
+    fn eject(&mut self, p: &Po, v: Vec2) -> bool;
+
+    // 点燃p处的可燃格子，已经在烧或者不可燃返回false
+    fn ignite(&mut self, p: &Po, reg: &MaterialRegistry) -> bool {
+        let Some(c) = self.get(p) else {
+            return false;
+        };
+        let mat = c.mat;
+        let Some(b) = reg.get(mat).burn.as_ref() else {
+            return false;
+        };
+        if c.burn.is_some() {
+            return false;
+        }
+        if let Some(c) = self.get_mut(p) {
+            c.burn = Some(b.duration.max(1));
+            c.temp = c.temp.max(b.heat);
+        }
+        self.wake(p);
+        self.record_ignited(CellIgnited {p: *p, mat});
+        true
+    }
+}
+
+impl CellGrid for CellsMap {
+    fn get(&self, p: &Po) -> Option<&CellData> {
+        CellsMap::get(self, p)
+    }
+
+    fn get_mut(&mut self, p: &Po) -> Option<&mut CellData> {
+        CellsMap::get_mut(self, p)
+    }
+
+    fn get_mut_quiet(&mut self, p: &Po) -> Option<&mut CellData> {
+        CellsMap::get_mut_quiet(self, p)
+    }
+
+    fn swap(&mut self, a: &Po, b: &Po) {
+        CellsMap::swap(self, a, b)
+    }
+
+    fn wake(&mut self, p: &Po) {
+        CellsMap::wake(self, p)
+    }
+
+    fn add_heat(&mut self, p: &Po, delta: f32) -> bool {
+        CellsMap::add_heat(self, p, delta)
+    }
+
+    fn add(&mut self, p: &Po, c: CellData) -> Option<CellData> {
+        CellsMap::add(self, p, c)
+    }
+
+    fn del(&mut self, p: &Po) -> Option<CellData> {
+        CellsMap::del(self, p)
+    }
+
+    fn record_ignited(&mut self, ev: CellIgnited) {
+        self.ignited.push(ev);
+    }
+
+    fn record_reacted(&mut self, ev: CellReacted) {
+        self.reacted.push(ev);
+    }
+
+    fn record_flow(&mut self, from: &Po, to: &Po) {
+        self.flows.push((*from, *to));
+    }
+
+    fn eject(&mut self, p: &Po, v: Vec2) -> bool {
+        CellsMap::eject(self, p, v)
+    }
+}
+
+// 2x2棋盘格的批次数，同一批次的区块两两不相邻
+pub const CHECKER_PASSES: usize = 4;
+
+fn checker_pass(cp: &ChunkPo) -> usize {
+    let r = cp.rem_euclid(ChunkPo::splat(2));
+    (r.y * 2 + r.x) as usize
+}
+
+#[derive(Resource, Clone)]
 pub struct CellsMap {
-    map: HashMap<Po, Entity>,
+    chunks: HashMap<ChunkPo, Chunk>,
+    // 像素有变化、需要重新上传贴图的区块
+    dirty: HashSet<ChunkPo>,
+    // 还没发送的事件
+    ignited: Vec<CellIgnited>,
+    reacted: Vec<CellReacted>,
+    // 还没执行的液体流动
+    flows: Vec<(Po, Po)>,
+    // 刚被拿出网格、还没交给Particles的格子
+    ejected: Vec<(Po, Vec2, CellData)>,
 }
 
 impl Default for CellsMap {
     fn default() -> Self {
         Self {
-            map : HashMap::new()
+            chunks : HashMap::new(),
+            dirty: HashSet::new(),
+            ignited: Vec::new(),
+            reacted: Vec::new(),
+            flows: Vec::new(),
+            ejected: Vec::new(),
         }
     }
 }
 
 impl Iterator for CellsMap {
-    type Item = (Po, Entity);
+    type Item = (Po, CellData);
     fn next(&mut self) -> Option<Self::Item> {
-        let next_entry = self.map.iter().next().map(|(key, &value)| (key.clone(), value));
+        let next_entry = self.iter().next().map(|(key, &value)| (key, value));
         if let Some((key, _)) = &next_entry {
-            self.map.remove(key);
+            self.del(key);
         }
         next_entry
     }
 }
 
 impl CellsMap {
-    pub fn add(&mut self, p: &Po, e: &Entity) -> Option<Entity> {
-        self.map.insert(*p, *e)
+    pub fn add(&mut self, p: &Po, c: CellData) -> Option<CellData> {
+        let (cp, i) = po_to_chunk(p);
+        self.dirty.insert(cp);
+        let old = self.chunks.entry(cp).or_default().set(i, c);
+        self.wake(p);
+        old
+    }
+
+    pub fn del(&mut self, p: &Po) -> Option<CellData> {
+        let (cp, i) = po_to_chunk(p);
+        let old = self.chunks.get_mut(&cp)?.take(i);
+        self.dirty.insert(cp);
+        self.wake(p);
+        self.release_if_empty(&cp);
+        old
+    }
+
+    pub fn get(&self, p: &Po) -> Option<&CellData> {
+        let (cp, i) = po_to_chunk(p);
+        self.chunks.get(&cp)?.get(i)
+    }
+
+    pub fn get_mut(&mut self, p: &Po) -> Option<&mut CellData> {
+        let (cp, i) = po_to_chunk(p);
+        let chunk = self.chunks.get_mut(&cp)?;
+        chunk.set_awake(true);
+        let c = chunk.get_mut(i)?;
+        c.idle = 0;
+        self.dirty.insert(cp);
+        Some(c)
+    }
+
+    pub fn get_mut_quiet(&mut self, p: &Po) -> Option<&mut CellData> {
+        let (cp, i) = po_to_chunk(p);
+        self.chunks.get_mut(&cp)?.get_mut(i)
+    }
+
+    // 把a的格子移到b，b原来有格子则换到a
+    pub fn swap(&mut self, a: &Po, b: &Po) {
+        let (cpa, ia) = po_to_chunk(a);
+        let (cpb, ib) = po_to_chunk(b);
+        let ca = self.chunks.get_mut(&cpa).and_then(|c| c.take(ia));
+        let cb = self.chunks.get_mut(&cpb).and_then(|c| c.take(ib));
+        if let Some(c) = ca {
+            self.chunks.entry(cpb).or_default().set(ib, c);
+        }
+        if let Some(c) = cb {
+            self.chunks.entry(cpa).or_default().set(ia, c);
+        }
+        self.dirty.insert(cpa);
+        self.dirty.insert(cpb);
+        self.wake(a);
+        self.wake(b);
+        self.release_if_empty(&cpa);
+        self.release_if_empty(&cpb);
+    }
+
+    pub fn wake(&mut self, p: &Po) {
+        for q in std::iter::once(*p).chain(p.get_neighbors()) {
+            let (cp, i) = po_to_chunk(&q);
+            if let Some(chunk) = self.chunks.get_mut(&cp) {
+                if let Some(c) = chunk.get_mut(i) {
+                    c.idle = 0;
+                    chunk.set_awake(true);
+                }
+            }
+        }
+    }
+
+    pub fn temperature(&self, p: &Po) -> Option<f32> {
+        self.get(p).map(|c| c.temp)
+    }
+
+    // delta为负就是降温，不会低于绝对零度
+    pub fn add_heat(&mut self, p: &Po, delta: f32) -> bool {
+        let (cp, i) = po_to_chunk(p);
+        let Some(c) = self.chunks.get_mut(&cp).and_then(|c| c.get_mut(i)) else {
+            return false;
+        };
+        c.temp = (c.temp + delta).max(ABSOLUTE_ZERO);
+        if delta.abs() >= HEAT_WAKE {
+            self.wake(p);
+        }
+        true
+    }
+
+    // 以center为圆心、半径radius格的圆内所有格子
+    pub fn add_heat_area(&mut self, center: &Po, radius: i32, delta: f32) {
+        for dy in -radius..=radius {
+            for dx in -radius..=radius {
+                if dx * dx + dy * dy <= radius * radius {
+                    self.add_heat(&(*center + Po::new(dx, dy) * PIXEL_SIZE), delta);
+                }
+            }
+        }
+    }
+
+    pub fn ignite(&mut self, p: &Po, reg: &MaterialRegistry) -> bool {
+        CellGrid::ignite(self, p, reg)
     }
 
-    pub fn del(&mut self, p: &Po) -> Option<Entity> {
-        self.map.remove(p)
+    // 点燃圆内所有可燃格子，返回点燃的个数
+    pub fn ignite_area(&mut self, center: &Po, radius: i32, reg: &MaterialRegistry) -> usize {
+        let mut n = 0;
+        for dy in -radius..=radius {
+            for dx in -radius..=radius {
+                if dx * dx + dy * dy <= radius * radius && self.ignite(&(*center + Po::new(dx, dy) * PIXEL_SIZE), reg) {
+                    n += 1;
+                }
+            }
+        }
+        n
+    }
+
+    pub fn take_ignited(&mut self) -> Vec<CellIgnited> {
+        std::mem::take(&mut self.ignited)
+    }
+
+    pub fn take_reacted(&mut self) -> Vec<CellReacted> {
+        std::mem::take(&mut self.reacted)
+    }
+
+    pub fn eject(&mut self, p: &Po, v: Vec2) -> bool {
+        let Some(c) = self.del(p) else {
+            return false;
+        };
+        self.ejected.push((*p, v, c));
+        true
+    }
+
+    pub fn take_ejected(&mut self) -> Vec<(Po, Vec2, CellData)> {
+        std::mem::take(&mut self.ejected)
     }
 
-    pub fn get(&self, p: &Po) -> Option<&Entity> {
-        self.map.get(p)
+    // 执行记下的液体流动，同一个液面格子只会被挪走一次
+    // 挪之前再确认一遍from还是液面、to还空着且比from低
+    pub fn apply_flows(&mut self, tick: u32, reg: &MaterialRegistry) {
+        for (from, to) in std::mem::take(&mut self.flows) {
+            if to.y >= from.y || self.get(&to).is_some() {
+                continue;
+            }
+            let Some(c) = self.get(&from) else {
+                continue;
+            };
+            if c.tick == tick || reg.get(c.mat).class != MoveClass::Liquid {
+                continue;
+            }
+            if self.get(&(from + Po::new(0, PIXEL_SIZE))).is_some_and(|nc| reg.get(nc.mat).class != MoveClass::Gas) {
+                continue;
+            }
+            self.swap(&from, &to);
+            if let Some(c) = self.get_mut(&to) {
+                c.v = CellVelocity::default();
+                c.tick = tick;
+                c.pressure = 0.;
+                c.surface = None;
+            }
+        }
+    }
+
+    // 区块空了就释放
+    fn release_if_empty(&mut self, cp: &ChunkPo) {
+        if self.chunks.get(cp).is_some_and(|c| c.is_empty()) {
+            self.chunks.remove(cp);
+        }
     }
 
     pub fn clear(&mut self) {
-        self.map.clear();
+        self.dirty.extend(self.chunks.keys());
+        self.chunks.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.chunks.values().map(|c| c.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.chunks.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (Po, &CellData)> {
+        self.chunks.iter().flat_map(|(cp, c)| c.iter().map(move |(i, e)| (chunk_to_po(cp, i), e)))
+    }
+
+    pub fn get_chunk(&self, cp: &ChunkPo) -> Option<&Chunk> {
+        self.chunks.get(cp)
+    }
+
+    pub fn take_dirty(&mut self) -> HashSet<ChunkPo> {
+        std::mem::take(&mut self.dirty)
+    }
+
+    pub fn chunks(&self) -> impl Iterator<Item = (&ChunkPo, &Chunk)> {
+        self.chunks.iter()
+    }
+
+    // 给醒着的区块补齐周围8个区块，并行更新时格子才能移进去
+    pub fn prepare_neighbors(&mut self) {
+        let cps: Vec<ChunkPo> = self.chunks.iter()
+            .filter(|(_, c)| !c.is_empty() && c.is_awake())
+            .map(|(cp, _)| *cp)
+            .collect();
+        for cp in cps {
+            for k in 0..9 {
+                self.chunks.entry(cp + window_offset(k)).or_default();
+            }
+        }
+    }
+
+    // 为第pass批棋盘格里醒着的区块创建窗口，同一批的中心区块两两不相邻，窗口不会同时写同一个格子
+    pub(crate) fn windows(&mut self, pass: usize) -> Vec<ChunkWindow<'_>> {
+        let mut centers: Vec<ChunkPo> = self.chunks.iter()
+            .filter(|(cp, c)| !c.is_empty() && c.is_awake() && checker_pass(cp) == pass)
+            .map(|(cp, _)| *cp)
+            .collect();
+        // 按坐标排序，之后合并事件、流动和粒子的顺序不受HashMap影响
+        centers.sort_unstable_by_key(|cp| (cp.y, cp.x));
+        // 只给窗口用到的区块拿指针，拿指针会分配格子数组
+        let used: HashSet<ChunkPo> = centers.iter()
+            .flat_map(|cp| (0..9).map(move |k| *cp + window_offset(k)))
+            .collect();
+        let ptrs: HashMap<ChunkPo, *mut Option<CellData>> = self.chunks.iter_mut()
+            .filter(|(cp, _)| used.contains(*cp))
+            .map(|(cp, c)| (*cp, c.slots_ptr()))
+            .collect();
+        centers.into_iter().map(|center| {
+            let mut slots = [std::ptr::null_mut(); 9];
+            for (k, slot) in slots.iter_mut().enumerate() {
+                if let Some(ptr) = ptrs.get(&(center + window_offset(k))) {
+                    *slot = *ptr;
+                }
+            }
+            ChunkWindow::new(center, slots)
+        }).collect()
+    }
+
+    pub fn apply_window_deltas(&mut self, deltas: Vec<WindowDelta>) {
+        for mut delta in deltas {
+            self.ignited.append(&mut delta.ignited);
+            self.reacted.append(&mut delta.reacted);
+            self.flows.append(&mut delta.flows);
+            self.ejected.append(&mut delta.ejected);
+            if let Some(c) = self.chunks.get_mut(&delta.center) {
+                c.set_awake(delta.center_awake);
+            }
+            for k in 0..9 {
+                let cp = delta.center + window_offset(k);
+                if let Some(c) = self.chunks.get_mut(&cp) {
+                    c.apply_len_delta(delta.len_delta[k]);
+                    if delta.woken[k] {
+                        c.set_awake(true);
+                    }
+                }
+                if delta.dirty[k] {
+                    self.dirty.insert(cp);
+                }
+            }
+        }
+    }
+
+    // 释放空区块，有格子的区块周围的空区块先留着，下一帧还要用，但不占格子数组
+    pub fn release_empty(&mut self) {
+        let keep: HashSet<ChunkPo> = self.chunks.iter()
+            .filter(|(_, c)| !c.is_empty())
+            .flat_map(|(cp, _)| (0..9).map(move |k| *cp + window_offset(k)))
+            .collect();
+        let dirty = &mut self.dirty;
+        self.chunks.retain(|cp, c| {
+            if !c.is_empty() {
+                true
+            } else if keep.contains(cp) {
+                c.free_slots();
+                true
+            } else {
+                dirty.insert(*cp);
+                false
+            }
+        });
     }
 
-    pub fn get_neighbors<T: Sized>(&self, neihbor_po: [Po; 8]) -> Vec<Option<&Entity>> {
+    pub fn get_neighbors<T: Sized>(&self, neihbor_po: [Po; 8]) -> Vec<Option<&CellData>> {
         neihbor_po.iter().map(|p| self.get(p)).collect()
     }
 
@@ -66,7 +450,7 @@ impl CellsMap {
                 transform: Transform::from_xyz(
                     (p.x + 5*PIXEL_SIZE) as f32, 
                     p.y as f32,
-                    0.
+                    DEBUG_Z,
                 ),
                 ..default()
             }, DebugMask));
@@ -76,7 +460,7 @@ impl CellsMap {
     }
 
     pub fn show_debug_info_all(&self, cmds: &mut Commands) {
-        for (p, _e) in self.map.iter() {
+        for (p, _e) in self.iter() {
             cmds.spawn((
                 SpriteBundle {
                     sprite: Sprite {
@@ -87,7 +471,7 @@ impl CellsMap {
                     transform: Transform::from_xyz(
                         (p.x-1) as f32,
                         (p.y-1) as f32,
-                        0.5,
+                        DEBUG_Z,
                     ),
                     ..default()
                 },
@@ -98,4 +482,4 @@ impl CellsMap {
 }
 
 #[derive(Component)]
-pub struct DebugMask;
\ No newline at end of file
+pub struct DebugMask;
@@ -0,0 +1,117 @@
+use bevy::prelude::*;
+use crate::comm::*;
+use crate::components::CellData;
+
+// 区块边长(格子数)
+pub const CHUNK_SIZE: i32 = 64;
+pub const CHUNK_AREA: usize = (CHUNK_SIZE * CHUNK_SIZE) as usize;
+
+pub type ChunkPo = IVec2;
+
+// 世界坐标 -> (区块坐标, 区块内下标)
+pub fn po_to_chunk(p: &Po) -> (ChunkPo, usize) {
+    let x = p.x.div_euclid(PIXEL_SIZE);
+    let y = p.y.div_euclid(PIXEL_SIZE);
+    let cp = ChunkPo {x: x.div_euclid(CHUNK_SIZE), y: y.div_euclid(CHUNK_SIZE)};
+    let lx = x.rem_euclid(CHUNK_SIZE);
+    let ly = y.rem_euclid(CHUNK_SIZE);
+    (cp, (ly * CHUNK_SIZE + lx) as usize)
+}
+
+// (区块坐标, 区块内下标) -> 世界坐标
+pub fn chunk_to_po(cp: &ChunkPo, i: usize) -> Po {
+    let lx = i as i32 % CHUNK_SIZE;
+    let ly = i as i32 / CHUNK_SIZE;
+    Po {
+        x: (cp.x * CHUNK_SIZE + lx) * PIXEL_SIZE,
+        y: (cp.y * CHUNK_SIZE + ly) * PIXEL_SIZE,
+    }
+}
+
+#[derive(Clone)]
+pub struct Chunk {
+    // 空区块不分配，第一次放格子或者拿指针时才分配
+    slots: Box<[Option<CellData>]>,
+    len: usize,
+    // 有没休眠的格子，没有的区块整个跳过
+    awake: bool,
+}
+
+impl Default for Chunk {
+    fn default() -> Self {
+        Self {
+            slots: Box::default(),
+            len: 0,
+            awake: true,
+        }
+    }
+}
+
+impl Chunk {
+    pub fn set(&mut self, i: usize, c: CellData) -> Option<CellData> {
+        self.alloc();
+        let old = self.slots[i].replace(c);
+        if old.is_none() {
+            self.len += 1;
+        }
+        old
+    }
+
+    pub fn take(&mut self, i: usize) -> Option<CellData> {
+        let old = self.slots.get_mut(i)?.take();
+        if old.is_some() {
+            self.len -= 1;
+        }
+        old
+    }
+
+    pub fn get(&self, i: usize) -> Option<&CellData> {
+        self.slots.get(i)?.as_ref()
+    }
+
+    pub fn get_mut(&mut self, i: usize) -> Option<&mut CellData> {
+        self.slots.get_mut(i)?.as_mut()
+    }
+
+    pub(crate) fn slots_ptr(&mut self) -> *mut Option<CellData> {
+        self.alloc();
+        self.slots.as_mut_ptr()
+    }
+
+    fn alloc(&mut self) {
+        if self.slots.is_empty() {
+            self.slots = vec![None; CHUNK_AREA].into_boxed_slice();
+        }
+    }
+
+    // 空了就把格子数组还回去，区块本身留着
+    pub(crate) fn free_slots(&mut self) {
+        if self.is_empty() {
+            self.slots = Box::default();
+        }
+    }
+
+    pub(crate) fn apply_len_delta(&mut self, d: i32) {
+        self.len = (self.len as i32 + d) as usize;
+    }
+
+    pub fn is_awake(&self) -> bool {
+        self.awake
+    }
+
+    pub fn set_awake(&mut self, awake: bool) {
+        self.awake = awake;
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &CellData)> {
+        self.slots.iter().enumerate().filter_map(|(i, c)| c.as_ref().map(|c| (i, c)))
+    }
+}
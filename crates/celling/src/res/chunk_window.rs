@@ -0,0 +1,297 @@
+use std::marker::PhantomData;
+use std::ptr;
+
+use bevy::prelude::Vec2;
+
+use crate::comm::*;
+use crate::components::{CellData, CellVelocity, ABSOLUTE_ZERO, HEAT_WAKE};
+use super::chunk::*;
+use super::cells_map::{CellGrid, CellsMap};
+use super::cell_events::*;
+
+// 一个区块所能读写的最大距离(格子数)，不能超过半个区块
+// 否则棋盘格同一批次里相隔一个区块的两个任务会写到同一个格子
+pub const WINDOW_REACH: i32 = CHUNK_SIZE / 2 - 1;
+
+// 以一个区块为中心的3x3区块视图，供并行更新时直接读写
+// 只在同一批次的中心区块互不相邻时使用，见CellsMap::windows
+pub struct ChunkWindow<'a> {
+    center: ChunkPo,
+    slots: [*mut Option<CellData>; 9],
+    len_delta: [i32; 9],
+    dirty: [bool; 9],
+    woken: [bool; 9],
+    center_awake: bool,
+    ignited: Vec<CellIgnited>,
+    reacted: Vec<CellReacted>,
+    flows: Vec<(Po, Po)>,
+    ejected: Vec<(Po, Vec2, CellData)>,
+    _marker: PhantomData<&'a mut CellsMap>,
+}
+
+// 窗口之间只会访问互不重叠的格子
+unsafe impl Send for ChunkWindow<'_> {}
+
+// 窗口更新完之后需要写回区块的信息
+pub struct WindowDelta {
+    pub center: ChunkPo,
+    pub len_delta: [i32; 9],
+    pub dirty: [bool; 9],
+    pub woken: [bool; 9],
+    pub center_awake: bool,
+    pub ignited: Vec<CellIgnited>,
+    pub reacted: Vec<CellReacted>,
+    pub flows: Vec<(Po, Po)>,
+    pub ejected: Vec<(Po, Vec2, CellData)>,
+}
+
+fn window_index(center: &ChunkPo, cp: &ChunkPo) -> Option<usize> {
+    let d = *cp - *center;
+    if d.x.abs() > 1 || d.y.abs() > 1 {
+        return None;
+    }
+    Some(((d.y + 1) * 3 + d.x + 1) as usize)
+}
+
+pub fn window_offset(k: usize) -> ChunkPo {
+    ChunkPo {x: k as i32 % 3 - 1, y: k as i32 / 3 - 1}
+}
+
+// p在中心区块之外的格子数(两个方向取大的)，在中心区块里是0
+fn reach_from(center: &ChunkPo, p: &Po) -> i32 {
+    let cell = p.div_euclid(Po::splat(PIXEL_SIZE));
+    let min = *center * CHUNK_SIZE;
+    let max = min + Po::splat(CHUNK_SIZE - 1);
+    (min - cell).max(cell - max).max(Po::ZERO).max_element()
+}
+
+impl<'a> ChunkWindow<'a> {
+    pub(crate) fn new(center: ChunkPo, slots: [*mut Option<CellData>; 9]) -> Self {
+        Self {
+            center,
+            slots,
+            len_delta: [0; 9],
+            dirty: [false; 9],
+            woken: [false; 9],
+            center_awake: true,
+            ignited: Vec::new(),
+            reacted: Vec::new(),
+            flows: Vec::new(),
+            ejected: Vec::new(),
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn center(&self) -> ChunkPo {
+        self.center
+    }
+
+    fn slot(&self, p: &Po) -> Option<(usize, *mut Option<CellData>)> {
+        // 超出WINDOW_REACH的读写可能和同批次的其他窗口撞上
+        assert!(
+            reach_from(&self.center, p) <= WINDOW_REACH,
+            "{:?} is out of the reach of chunk {:?}", p, self.center,
+        );
+        let (cp, i) = po_to_chunk(p);
+        let k = window_index(&self.center, &cp)?;
+        let base = self.slots[k];
+        if base.is_null() {
+            return None;
+        }
+        // SAFETY: i < CHUNK_AREA，base指向一个长度为CHUNK_AREA的区块
+        Some((k, unsafe { base.add(i) }))
+    }
+
+    // 中心区块里所有格子的坐标
+    pub fn center_cells(&self) -> Vec<Po> {
+        let base = self.slots[4];
+        (0..CHUNK_AREA)
+            // SAFETY: 中心区块只有当前任务会读写
+            .filter(|i| unsafe { (*base.add(*i)).is_some() })
+            .map(|i| chunk_to_po(&self.center, i))
+            .collect()
+    }
+
+    // 本帧没动的中心格子累计空闲帧数、速度清零，返回中心区块是否还有醒着的格子
+    pub fn rest_center(&mut self, tick: u32) -> bool {
+        let base = self.slots[4];
+        let mut awake = false;
+        for i in 0..CHUNK_AREA {
+            // SAFETY: 中心区块只有当前任务会读写
+            if let Some(c) = unsafe { (*base.add(i)).as_mut() } {
+                if c.tick != tick {
+                    c.idle = c.idle.saturating_add(1);
+                    c.v = CellVelocity::default();
+                }
+                c.age = c.age.saturating_add(1);
+                awake |= !c.is_sleeping();
+            }
+        }
+        self.center_awake = awake;
+        awake
+    }
+
+    pub fn finish(self) -> WindowDelta {
+        WindowDelta {
+            center: self.center,
+            len_delta: self.len_delta,
+            dirty: self.dirty,
+            woken: self.woken,
+            center_awake: self.center_awake,
+            ignited: self.ignited,
+            reacted: self.reacted,
+            flows: self.flows,
+            ejected: self.ejected,
+        }
+    }
+}
+
+impl<'a> CellGrid for ChunkWindow<'a> {
+    fn get(&self, p: &Po) -> Option<&CellData> {
+        let (_, slot) = self.slot(p)?;
+        // SAFETY: 见WINDOW_REACH
+        unsafe { (*slot).as_ref() }
+    }
+
+    fn get_mut(&mut self, p: &Po) -> Option<&mut CellData> {
+        let (k, slot) = self.slot(p)?;
+        self.dirty[k] = true;
+        // SAFETY: 见WINDOW_REACH
+        unsafe { (*slot).as_mut() }
+    }
+
+    fn get_mut_quiet(&mut self, p: &Po) -> Option<&mut CellData> {
+        let (_, slot) = self.slot(p)?;
+        // SAFETY: 见WINDOW_REACH
+        unsafe { (*slot).as_mut() }
+    }
+
+    fn wake(&mut self, p: &Po) {
+        for q in std::iter::once(*p).chain(p.get_neighbors()) {
+            if let Some((k, slot)) = self.slot(&q) {
+                // SAFETY: 见WINDOW_REACH
+                if let Some(c) = unsafe { (*slot).as_mut() } {
+                    c.idle = 0;
+                    self.woken[k] = true;
+                }
+            }
+        }
+    }
+
+    fn add_heat(&mut self, p: &Po, delta: f32) -> bool {
+        let Some((_, slot)) = self.slot(p) else {
+            return false;
+        };
+        // SAFETY: 见WINDOW_REACH
+        let Some(c) = (unsafe { (*slot).as_mut() }) else {
+            return false;
+        };
+        c.temp = (c.temp + delta).max(ABSOLUTE_ZERO);
+        if delta.abs() >= HEAT_WAKE {
+            self.wake(p);
+        }
+        true
+    }
+
+    // 超出窗口的格子直接丢掉
+    fn add(&mut self, p: &Po, c: CellData) -> Option<CellData> {
+        let (k, slot) = self.slot(p)?;
+        // SAFETY: 见WINDOW_REACH
+        let old = unsafe { (*slot).replace(c) };
+        if old.is_none() {
+            self.len_delta[k] += 1;
+        }
+        self.dirty[k] = true;
+        self.wake(p);
+        old
+    }
+
+    fn del(&mut self, p: &Po) -> Option<CellData> {
+        let (k, slot) = self.slot(p)?;
+        // SAFETY: 见WINDOW_REACH
+        let old = unsafe { (*slot).take() }?;
+        self.len_delta[k] -= 1;
+        self.dirty[k] = true;
+        self.wake(p);
+        Some(old)
+    }
+
+    fn record_ignited(&mut self, ev: CellIgnited) {
+        self.ignited.push(ev);
+    }
+
+    fn record_reacted(&mut self, ev: CellReacted) {
+        self.reacted.push(ev);
+    }
+
+    fn record_flow(&mut self, from: &Po, to: &Po) {
+        self.flows.push((*from, *to));
+    }
+
+    fn eject(&mut self, p: &Po, v: Vec2) -> bool {
+        let Some(c) = self.del(p) else {
+            return false;
+        };
+        self.ejected.push((*p, v, c));
+        true
+    }
+
+    fn swap(&mut self, a: &Po, b: &Po) {
+        // 超出窗口的移动直接忽略
+        let (Some((ka, sa)), Some((kb, sb))) = (self.slot(a), self.slot(b)) else {
+            return;
+        };
+        // SAFETY: 见WINDOW_REACH
+        unsafe {
+            let (ca, cb) = ((*sa).is_some(), (*sb).is_some());
+            ptr::swap(sa, sb);
+            if ca != cb {
+                let d = if ca { 1 } else { -1 };
+                self.len_delta[kb] += d;
+                self.len_delta[ka] -= d;
+            }
+        }
+        self.dirty[ka] = true;
+        self.dirty[kb] = true;
+        self.wake(a);
+        self.wake(b);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::utils::HashSet;
+
+    use super::*;
+    use crate::components::{CellBundle, CellDir};
+    use crate::res::{MaterialId, CHECKER_PASSES};
+
+    // 同一批次的窗口在WINDOW_REACH之内能碰到的格子互不重叠
+    #[test]
+    fn same_pass_windows_are_disjoint() {
+        let mut map = CellsMap::default();
+        let c = CellData::new(CellBundle {mat: MaterialId(0), cd: CellDir::None}, bevy::prelude::Color::WHITE);
+        for y in -3..3 {
+            for x in -3..3 {
+                map.add(&chunk_to_po(&ChunkPo::new(x, y), 0), c);
+            }
+        }
+        for pass in 0..CHECKER_PASSES {
+            let windows = map.windows(pass);
+            assert_eq!(windows.len(), 9);
+            let mut seen = HashSet::new();
+            for w in &windows {
+                let origin = w.center() * CHUNK_SIZE - Po::splat(WINDOW_REACH);
+                let size = CHUNK_SIZE + 2 * WINDOW_REACH;
+                for dy in 0..size {
+                    for dx in 0..size {
+                        let p = (origin + Po::new(dx, dy)) * PIXEL_SIZE;
+                        if let Some((_, slot)) = w.slot(&p) {
+                            assert!(seen.insert(slot as usize), "{:?} is shared by two windows", p);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
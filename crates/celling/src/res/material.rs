@@ -0,0 +1,647 @@
+use std::fmt;
+
+use bevy::prelude::*;
+use bevy::asset::{AssetLoader, AsyncReadExt, LoadContext, io::Reader};
+use bevy::utils::{BoxedFuture, HashMap};
+use serde::Deserialize;
+
+use crate::comm::*;
+use crate::components::AMBIENT_TEMP;
+
+#[derive(Component, Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct MaterialId(pub u16);
+
+// 移动方式
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum MoveClass {
+    #[default]
+    Powder,
+    Liquid,
+    Gas,
+    Static,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MaterialFlag {
+    // 不会被撞飞
+    Indestructible,
+    // 火焰，会点燃旁边的可燃格子
+    Fire,
+}
+
+impl MaterialFlag {
+    fn bit(self) -> u32 {
+        1 << self as u32
+    }
+}
+
+// 温度高于above或低于below时原地变成into
+#[derive(Deserialize, Debug, Clone)]
+pub struct Transition {
+    #[serde(default)]
+    pub above: Option<f32>,
+    #[serde(default)]
+    pub below: Option<f32>,
+    pub into: String,
+    #[serde(skip)]
+    into_id: Option<MaterialId>,
+}
+
+impl Transition {
+    pub fn above(temp: f32, into: &str) -> Self {
+        Self {
+            above: Some(temp),
+            below: None,
+            into: into.to_string(),
+            into_id: None,
+        }
+    }
+
+    pub fn below(temp: f32, into: &str) -> Self {
+        Self {
+            above: None,
+            below: Some(temp),
+            into: into.to_string(),
+            into_id: None,
+        }
+    }
+
+    pub fn fires(&self, temp: f32) -> bool {
+        self.above.is_some_and(|t| temp > t) || self.below.is_some_and(|t| temp < t)
+    }
+
+    // into还没注册时为None
+    pub fn target(&self) -> Option<MaterialId> {
+        self.into_id
+    }
+}
+
+// 可燃材质的燃烧参数
+#[derive(Deserialize, Debug, Clone)]
+pub struct Burn {
+    // 温度超过这个值自己烧起来
+    pub ignite: f32,
+    // 烧多少帧
+    pub duration: u16,
+    // 烧的时候保持的最低温度
+    #[serde(default = "default_burn_heat")]
+    pub heat: f32,
+    // 每帧被旁边的火点燃的概率
+    #[serde(default)]
+    pub spread: f32,
+    // 烧完变成什么，None就是烧没了
+    #[serde(default)]
+    pub product: Option<String>,
+    #[serde(skip)]
+    product_id: Option<MaterialId>,
+}
+
+impl Burn {
+    pub fn new(ignite: f32, duration: u16, spread: f32, product: Option<&str>) -> Self {
+        Self {
+            ignite,
+            duration,
+            heat: default_burn_heat(),
+            spread,
+            product: product.map(str::to_string),
+            product_id: None,
+        }
+    }
+
+    // product还没注册时为None
+    pub fn product(&self) -> Option<MaterialId> {
+        self.product_id
+    }
+}
+
+// 反应后格子变成什么
+#[derive(Deserialize, Debug, Default, Clone, PartialEq)]
+pub enum Becomes {
+    // 不变
+    #[default]
+    Same,
+    // 消失
+    Nothing,
+    Material(String),
+}
+
+// 和相邻的with材质接触时按chance的概率反应，自己变成this，对方变成that
+#[derive(Deserialize, Debug, Clone)]
+pub struct Reaction {
+    pub with: String,
+    #[serde(default)]
+    pub this: Becomes,
+    #[serde(default)]
+    pub that: Becomes,
+    #[serde(default = "default_chance")]
+    pub chance: f32,
+    #[serde(skip)]
+    with_id: Option<MaterialId>,
+    #[serde(skip)]
+    this_id: Option<MaterialId>,
+    #[serde(skip)]
+    that_id: Option<MaterialId>,
+}
+
+impl Reaction {
+    pub fn new(with: &str, this: Becomes, that: Becomes, chance: f32) -> Self {
+        Self {
+            with: with.to_string(),
+            this,
+            that,
+            chance,
+            with_id: None,
+            this_id: None,
+            that_id: None,
+        }
+    }
+
+    // with还没注册时为None
+    pub fn with_id(&self) -> Option<MaterialId> {
+        self.with_id
+    }
+
+    // 反应后的材质，Same返回原来的，Nothing返回None
+    pub fn this_into(&self, this: MaterialId) -> Option<MaterialId> {
+        match self.this {
+            Becomes::Same => Some(this),
+            _ => self.this_id,
+        }
+    }
+
+    pub fn that_into(&self, that: MaterialId) -> Option<MaterialId> {
+        match self.that {
+            Becomes::Same => Some(that),
+            _ => self.that_id,
+        }
+    }
+
+    // 产物的材质都已注册才能反应
+    pub fn is_resolved(&self) -> bool {
+        self.with_id.is_some()
+            && (!matches!(self.this, Becomes::Material(_)) || self.this_id.is_some())
+            && (!matches!(self.that, Becomes::Material(_)) || self.that_id.is_some())
+    }
+}
+
+// 气体的扩散参数
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct GasParams {
+    // 每帧凭空消失的概率
+    pub dissipation: f32,
+    // 每帧往随机方向的空位乱跑的概率
+    pub diffusion: f32,
+    // 上面堵住时一帧最多横着走几格
+    pub spread: u8,
+}
+
+impl Default for GasParams {
+    fn default() -> Self {
+        Self {
+            dissipation: 0.,
+            diffusion: 0.,
+            spread: 1,
+        }
+    }
+}
+
+impl GasParams {
+    pub fn new(dissipation: f32, diffusion: f32, spread: u8) -> Self {
+        Self {
+            dissipation,
+            diffusion,
+            spread,
+        }
+    }
+}
+
+// 粉末的摩擦参数，决定堆起来的坡度
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct PowderParams {
+    // 有东西垫着时每帧停止滑动的概率，越大堆得越陡
+    pub friction: f32,
+    // 停住的颗粒被旁边动起来的颗粒带动的阻力，0~1
+    pub inertia: f32,
+}
+
+impl PowderParams {
+    pub fn new(friction: f32, inertia: f32) -> Self {
+        Self {
+            friction,
+            inertia,
+        }
+    }
+}
+
+// 液体的流动参数
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct LiquidParams {
+    // 一帧最多横着流几格
+    pub dispersion: u8,
+}
+
+impl Default for LiquidParams {
+    fn default() -> Self {
+        Self {
+            dispersion: 1,
+        }
+    }
+}
+
+impl LiquidParams {
+    pub fn new(dispersion: u8) -> Self {
+        Self {
+            dispersion,
+        }
+    }
+}
+
+fn default_chance() -> f32 {
+    1.
+}
+
+fn default_burn_heat() -> f32 {
+    600.
+}
+
+const DEFAULT_CONDUCTIVITY: f32 = 0.1;
+const DEFAULT_HARDNESS: f32 = 1.;
+
+fn default_temperature() -> f32 {
+    AMBIENT_TEMP
+}
+
+fn default_conductivity() -> f32 {
+    DEFAULT_CONDUCTIVITY
+}
+
+fn default_hardness() -> f32 {
+    DEFAULT_HARDNESS
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Material {
+    #[serde(skip)]
+    pub id: MaterialId,
+    pub name: String,
+    pub class: MoveClass,
+    // 密度，下落时能挤开更轻的流体，上升时能挤开更重的
+    pub density: i32,
+    // 粘度，0~1，越大越难被挤开，液体每帧按规则移动(下落、横流)的概率也越小
+    #[serde(default)]
+    pub viscosity: f32,
+    // 新建格子时的温度
+    #[serde(default = "default_temperature")]
+    pub temperature: f32,
+    // 导热系数，0~1
+    #[serde(default = "default_conductivity")]
+    pub conductivity: f32,
+    // 热源每帧都保持这个温度，比如火
+    #[serde(default)]
+    pub heat_source: Option<f32>,
+    #[serde(default)]
+    pub transitions: Vec<Transition>,
+    #[serde(default)]
+    pub burn: Option<Burn>,
+    // 存在多少帧后消失
+    #[serde(default)]
+    pub lifetime: Option<u16>,
+    #[serde(default)]
+    pub reactions: Vec<Reaction>,
+    // 爆炸威力衰减到这个值以下就炸不掉，和Explode的power同一单位
+    #[serde(default = "default_hardness")]
+    pub hardness: f32,
+    // 被炸碎后变成的材质，没有就直接消失
+    #[serde(default)]
+    pub debris: Option<String>,
+    #[serde(skip)]
+    debris_id: Option<MaterialId>,
+    // 只对粉末有用
+    #[serde(default)]
+    pub powder: PowderParams,
+    // 只对液体有用
+    #[serde(default)]
+    pub liquid: LiquidParams,
+    // 只对气体有用
+    #[serde(default)]
+    pub gas: GasParams,
+    // 新建格子时从里面挑一个颜色
+    pub colors: Vec<[u8; 4]>,
+    #[serde(default)]
+    pub flags: Vec<MaterialFlag>,
+    #[serde(skip)]
+    flag_bits: u32,
+}
+
+impl Material {
+    pub fn new(name: &str, class: MoveClass, density: i32, colors: Vec<[u8; 4]>) -> Self {
+        Self {
+            id: MaterialId::default(),
+            name: name.to_string(),
+            class,
+            density,
+            viscosity: 0.,
+            temperature: AMBIENT_TEMP,
+            conductivity: DEFAULT_CONDUCTIVITY,
+            heat_source: None,
+            transitions: Vec::new(),
+            burn: None,
+            lifetime: None,
+            reactions: Vec::new(),
+            hardness: DEFAULT_HARDNESS,
+            debris: None,
+            debris_id: None,
+            powder: PowderParams::default(),
+            liquid: LiquidParams::default(),
+            gas: GasParams::default(),
+            colors,
+            flags: Vec::new(),
+            flag_bits: 0,
+        }
+    }
+
+    pub fn with_flag(mut self, flag: MaterialFlag) -> Self {
+        self.flags.push(flag);
+        self
+    }
+
+    pub fn with_viscosity(mut self, viscosity: f32) -> Self {
+        self.viscosity = viscosity;
+        self
+    }
+
+    pub fn with_temperature(mut self, temperature: f32) -> Self {
+        self.temperature = temperature;
+        self
+    }
+
+    pub fn with_conductivity(mut self, conductivity: f32) -> Self {
+        self.conductivity = conductivity;
+        self
+    }
+
+    pub fn with_heat_source(mut self, temp: f32) -> Self {
+        self.heat_source = Some(temp);
+        self
+    }
+
+    pub fn with_transition(mut self, t: Transition) -> Self {
+        self.transitions.push(t);
+        self
+    }
+
+    pub fn with_burn(mut self, burn: Burn) -> Self {
+        self.burn = Some(burn);
+        self
+    }
+
+    pub fn with_lifetime(mut self, ticks: u16) -> Self {
+        self.lifetime = Some(ticks);
+        self
+    }
+
+    pub fn with_reaction(mut self, reaction: Reaction) -> Self {
+        self.reactions.push(reaction);
+        self
+    }
+
+    pub fn with_hardness(mut self, hardness: f32) -> Self {
+        self.hardness = hardness;
+        self
+    }
+
+    pub fn with_debris(mut self, debris: &str) -> Self {
+        self.debris = Some(debris.to_string());
+        self
+    }
+
+    // 解析出来的碎片材质
+    pub fn debris(&self) -> Option<MaterialId> {
+        self.debris_id
+    }
+
+    pub fn with_powder(mut self, powder: PowderParams) -> Self {
+        self.powder = powder;
+        self
+    }
+
+    pub fn with_liquid(mut self, liquid: LiquidParams) -> Self {
+        self.liquid = liquid;
+        self
+    }
+
+    pub fn with_gas(mut self, gas: GasParams) -> Self {
+        self.gas = gas;
+        self
+    }
+
+    // 调色板的不透明度，气体淡出时以它为准
+    pub fn alpha(&self) -> u8 {
+        self.colors.first().map_or(255, |c| c[3])
+    }
+
+    pub fn has_flag(&self, flag: MaterialFlag) -> bool {
+        self.flag_bits & flag.bit() != 0
+    }
+
+    pub fn is_movable(&self) -> bool {
+        self.class != MoveClass::Static
+    }
+
+    pub fn is_fluid(&self) -> bool {
+        matches!(self.class, MoveClass::Liquid | MoveClass::Gas)
+    }
+
+    // 按位置挑颜色，同一位置结果固定
+    pub fn pick_color(&self, p: &Po) -> Color {
+        let [r, g, b, a] = if self.colors.is_empty() {
+            [255, 0, 255, 255]
+        } else {
+            let h = (p.x.wrapping_mul(73856093) ^ p.y.wrapping_mul(19349663)) as u32;
+            self.colors[h as usize % self.colors.len()]
+        };
+        Color::rgba_u8(r, g, b, a)
+    }
+}
+
+// 材质表，格子只存MaterialId，行为都从这里查
+#[derive(Resource, Debug, Clone)]
+pub struct MaterialRegistry {
+    materials: Vec<Material>,
+    names: HashMap<String, MaterialId>,
+}
+
+impl Default for MaterialRegistry {
+    fn default() -> Self {
+        let mut reg = Self {
+            materials: Vec::new(),
+            names: HashMap::new(),
+        };
+        reg.register(Material::new("sand", MoveClass::Powder, 1600, vec![[0, 0, 0, 255]])
+            .with_powder(PowderParams::new(0.05, 0.2)));
+        reg.register(Material::new("water", MoveClass::Liquid, 1000, vec![[84, 107, 181, 255]])
+            .with_conductivity(0.15)
+            .with_liquid(LiquidParams::new(5))
+            .with_transition(Transition::above(100., "steam"))
+            .with_transition(Transition::below(0., "ice")));
+        // 新建的蒸汽是热的，冷下来凝结成水，一直没冷下来的慢慢散掉
+        reg.register(Material::new("steam", MoveClass::Gas, 1, vec![[220, 221, 213, 255]])
+            .with_temperature(110.)
+            .with_conductivity(0.05)
+            .with_gas(GasParams::new(0.002, 0.2, 4))
+            .with_transition(Transition::below(98., "water")));
+        reg.register(Material::new("stone", MoveClass::Static, 2600, vec![[0, 0, 0, 255]])
+            .with_conductivity(0.3)
+            .with_hardness(6.)
+            .with_debris("sand"));
+        reg.register(Material::new("ice", MoveClass::Static, 917, vec![[186, 218, 240, 255], [200, 228, 245, 255]])
+            .with_temperature(-10.)
+            .with_conductivity(0.2)
+            .with_hardness(3.)
+            .with_transition(Transition::above(0.5, "water")));
+        reg.register(Material::new("fire", MoveClass::Gas, 0, vec![[255, 90, 20, 255], [255, 160, 30, 255], [255, 210, 60, 255]])
+            .with_flag(MaterialFlag::Fire)
+            .with_heat_source(900.)
+            .with_lifetime(20)
+            .with_gas(GasParams::new(0., 0.4, 1)));
+        reg.register(Material::new("smoke", MoveClass::Gas, 1, vec![[90, 90, 90, 255], [110, 108, 105, 255]])
+            .with_temperature(80.)
+            .with_conductivity(0.05)
+            .with_lifetime(150)
+            .with_gas(GasParams::new(0.005, 0.3, 3)));
+        reg.register(Material::new("ash", MoveClass::Powder, 600, vec![[70, 70, 70, 255], [95, 92, 90, 255]])
+            .with_powder(PowderParams::new(0.3, 0.5)));
+        reg.register(Material::new("wood", MoveClass::Static, 700, vec![[111, 78, 45, 255], [124, 88, 52, 255]])
+            .with_conductivity(0.05)
+            .with_hardness(3.)
+            .with_burn(Burn::new(300., 120, 0.05, Some("ash"))));
+        reg
+    }
+}
+
+impl MaterialRegistry {
+    // 同名材质覆盖原来的定义，id不变
+    pub fn register(&mut self, mut m: Material) -> MaterialId {
+        m.flag_bits = m.flags.iter().fold(0, |bits, f| bits | f.bit());
+        if let Some(id) = self.names.get(&m.name) {
+            m.id = *id;
+            let id = *id;
+            self.materials[id.0 as usize] = m;
+            self.resolve();
+            return id;
+        }
+        let id = MaterialId(self.materials.len() as u16);
+        m.id = id;
+        self.names.insert(m.name.clone(), id);
+        self.materials.push(m);
+        self.resolve();
+        id
+    }
+
+    // 按名字引用的其他材质可能后注册，每次注册后重新解析
+    fn resolve(&mut self) {
+        let names = &self.names;
+        for m in self.materials.iter_mut() {
+            for t in m.transitions.iter_mut() {
+                t.into_id = names.get(&t.into).copied();
+            }
+            if let Some(b) = m.burn.as_mut() {
+                b.product_id = b.product.as_ref().and_then(|n| names.get(n).copied());
+            }
+            m.debris_id = m.debris.as_ref().and_then(|n| names.get(n).copied());
+            let id_of = |b: &Becomes| match b {
+                Becomes::Material(n) => names.get(n).copied(),
+                _ => None,
+            };
+            for r in m.reactions.iter_mut() {
+                r.with_id = names.get(&r.with).copied();
+                r.this_id = id_of(&r.this);
+                r.that_id = id_of(&r.that);
+            }
+        }
+    }
+
+    pub fn get(&self, id: MaterialId) -> &Material {
+        &self.materials[id.0 as usize]
+    }
+
+    pub fn id(&self, name: &str) -> Option<MaterialId> {
+        self.names.get(name).copied()
+    }
+
+    pub fn by_name(&self, name: &str) -> Option<&Material> {
+        self.id(name).map(|id| self.get(id))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Material> {
+        self.materials.iter()
+    }
+}
+
+// *.materials.ron，加载后合并进MaterialRegistry
+#[derive(Asset, TypePath, Deserialize, Debug, Clone)]
+pub struct MaterialSet {
+    pub materials: Vec<Material>,
+}
+
+// 需要加载的材质文件，加载完由systems::materials合并
+#[derive(Resource, Default)]
+pub struct MaterialSetHandles(pub Vec<Handle<MaterialSet>>);
+
+#[derive(Default)]
+pub struct MaterialSetLoader;
+
+#[derive(Debug)]
+pub enum MaterialSetLoaderError {
+    Io(std::io::Error),
+    Ron(ron::error::SpannedError),
+}
+
+impl fmt::Display for MaterialSetLoaderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "could not read material set: {e}"),
+            Self::Ron(e) => write!(f, "could not parse material set: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for MaterialSetLoaderError {}
+
+impl From<std::io::Error> for MaterialSetLoaderError {
+    fn from(e: std::io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl From<ron::error::SpannedError> for MaterialSetLoaderError {
+    fn from(e: ron::error::SpannedError) -> Self {
+        Self::Ron(e)
+    }
+}
+
+impl AssetLoader for MaterialSetLoader {
+    type Asset = MaterialSet;
+    type Settings = ();
+    type Error = MaterialSetLoaderError;
+
+    fn load<'a>(
+        &'a self,
+        reader: &'a mut Reader,
+        _settings: &'a (),
+        _load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<MaterialSet, MaterialSetLoaderError>> {
+        Box::pin(async move {
+            let mut bytes = Vec::new();
+            reader.read_to_end(&mut bytes).await?;
+            Ok(ron::de::from_bytes::<MaterialSet>(&bytes)?)
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["materials.ron"]
+    }
+}
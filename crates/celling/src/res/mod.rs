@@ -1,6 +1,18 @@
+pub mod cell_events;
 pub mod cells_map;
+pub mod chunk;
+pub mod chunk_window;
+pub mod material;
+pub mod particle;
 pub mod settings;
+pub mod sim;
 
+pub use cell_events::*;
 pub use cells_map::*;
+pub use chunk::*;
+pub use chunk_window::*;
+pub use material::*;
+pub use particle::*;
+pub use sim::*;
 
 
@@ -0,0 +1,76 @@
+use bevy::prelude::*;
+
+use crate::comm::*;
+use crate::components::CellData;
+
+// 飞在空中的格子，不在CellsMap里，落地后变回格子
+#[derive(Debug, Clone, Copy)]
+pub struct Particle {
+    // 世界坐标
+    pub pos: Vec2,
+    // 上一个模拟帧的位置，渲染时在两帧之间插值
+    pub prev: Vec2,
+    // 单位是格子/帧
+    pub v: Vec2,
+    pub cell: CellData,
+    // 已经飞了多少帧
+    pub age: u16,
+    // 找不到空位、落地失败的次数
+    pub land_tries: u16,
+}
+
+impl Particle {
+    pub fn new(p: &Po, v: Vec2, cell: CellData) -> Self {
+        let pos = Vec2::new(p.x as f32, p.y as f32);
+        Self {
+            pos,
+            prev: pos,
+            v,
+            cell,
+            age: 0,
+            land_tries: 0,
+        }
+    }
+
+    // 所在的格子
+    pub fn po(&self) -> Po {
+        nearest_po(self.pos)
+    }
+
+    // 渲染帧在两个模拟帧之间的位置，alpha见SimTime::alpha
+    pub fn lerp_po(&self, alpha: f32) -> Po {
+        nearest_po(self.prev.lerp(self.pos, alpha))
+    }
+}
+
+// 所有在飞的粒子，每帧由systems::particles::update推进
+#[derive(Resource, Default, Clone)]
+pub struct Particles {
+    list: Vec<Particle>,
+}
+
+impl Particles {
+    pub fn spawn(&mut self, p: &Po, v: Vec2, cell: CellData) {
+        self.list.push(Particle::new(p, v, cell));
+    }
+
+    pub fn len(&self) -> usize {
+        self.list.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.list.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Particle> {
+        self.list.iter()
+    }
+
+    pub(crate) fn take(&mut self) -> Vec<Particle> {
+        std::mem::take(&mut self.list)
+    }
+
+    pub(crate) fn restore(&mut self, list: Vec<Particle>) {
+        self.list = list;
+    }
+}
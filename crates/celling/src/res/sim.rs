@@ -0,0 +1,165 @@
+use std::time::Duration;
+
+use bevy::prelude::*;
+use bevy::ecs::schedule::ScheduleLabel;
+use rand::{Error, RngCore};
+use crate::comm::*;
+
+// 格子模拟用的固定步长schedule，和渲染帧率无关
+#[derive(ScheduleLabel, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SimSchedule;
+
+// 模拟帧号，0留给新建的格子
+#[derive(Resource, Default, Clone, Copy, Debug)]
+pub struct SimTick(pub u32);
+
+// 每秒模拟帧数的下限，0或负数会让步长无穷大
+const MIN_TICK_RATE: f64 = 1.;
+
+fn tick_step(tick_rate: f64) -> Duration {
+    Duration::from_secs_f64(1. / tick_rate.max(MIN_TICK_RATE))
+}
+
+#[derive(Resource, Debug, Clone)]
+pub struct SimTime {
+    step: Duration,
+    // 一个渲染帧里最多追赶的模拟帧数，卡顿时丢掉多出来的时间
+    max_catch_up: u32,
+    accumulated: Duration,
+}
+
+impl SimTime {
+    pub fn new(tick_rate: f64, max_catch_up: u32) -> Self {
+        Self {
+            step: tick_step(tick_rate),
+            max_catch_up,
+            accumulated: Duration::ZERO,
+        }
+    }
+
+    pub fn step(&self) -> Duration {
+        self.step
+    }
+
+    pub fn set_tick_rate(&mut self, tick_rate: f64) {
+        self.step = tick_step(tick_rate);
+    }
+
+    pub fn set_max_catch_up(&mut self, max_catch_up: u32) {
+        self.max_catch_up = max_catch_up;
+    }
+
+    // 累加渲染帧时间，返回这一帧要跑几个模拟帧
+    pub fn accumulate(&mut self, delta: Duration) -> u32 {
+        self.accumulated += delta;
+        let mut n = 0;
+        while self.accumulated >= self.step && n < self.max_catch_up {
+            self.accumulated -= self.step;
+            n += 1;
+        }
+        if n == self.max_catch_up && self.accumulated >= self.step {
+            self.accumulated = Duration::ZERO;
+        }
+        n
+    }
+
+    // 距离上一个模拟帧过去了多少步长，渲染插值用，0~1
+    pub fn alpha(&self) -> f32 {
+        self.accumulated.as_secs_f32() / self.step.as_secs_f32()
+    }
+}
+
+// 所有随机都由(种子, 帧号, 位置)推出来，和执行顺序、线程无关，
+// 相同种子和输入下模拟结果完全一致
+#[derive(Resource, Clone, Copy, Debug)]
+pub struct SimRng {
+    seed: u64,
+}
+
+impl SimRng {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            seed
+        }
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    pub fn cell_rng(&self, tick: u32, p: &Po) -> CellRng {
+        self.cell_rng_salted(tick, p, 0)
+    }
+
+    // 同一格子同一帧里不同用途的随机用不同的salt，见下面的*_SALT
+    pub fn cell_rng_salted(&self, tick: u32, p: &Po, salt: u64) -> CellRng {
+        let po = ((p.x as u32 as u64) << 32) | p.y as u32 as u64;
+        self.rng(tick, po ^ salt.wrapping_mul(0x9E3779B97F4A7C15))
+    }
+
+    // salt用来区分同一帧里不同用途的随机
+    pub fn rng(&self, tick: u32, salt: u64) -> CellRng {
+        let h = mix(self.seed);
+        let h = mix(h ^ tick as u64);
+        CellRng {
+            state: mix(h ^ salt),
+        }
+    }
+}
+
+// cell_rng_salted用的salt，同一格子同一帧里各个用途的随机互不相关，不能重复，0留给移动
+pub(crate) const SPLASH_SALT: u64 = 0x5B1A;
+pub(crate) const FIRE_SALT: u64 = 0xF1AE;
+pub(crate) const REACT_SALT: u64 = 0x4EAC;
+pub(crate) const GAS_SALT: u64 = 0x6A5;
+pub(crate) const POWDER_SALT: u64 = 0x5A4D;
+pub(crate) const EXPLODE_SALT: u64 = 0xB0B;
+pub(crate) const RIGID_SALT: u64 = 0x41D;
+
+// splitmix64
+fn mix(mut z: u64) -> u64 {
+    z = z.wrapping_add(0x9E3779B97F4A7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+    z ^ (z >> 31)
+}
+
+pub struct CellRng {
+    state: u64,
+}
+
+impl RngCore for CellRng {
+    fn next_u32(&mut self) -> u32 {
+        (self.next_u64() >> 32) as u32
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E3779B97F4A7C15);
+        mix(self.state)
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        for chunk in dest.chunks_mut(8) {
+            let bytes = self.next_u64().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::utils::HashSet;
+
+    use super::*;
+
+    #[test]
+    fn salts_are_distinct() {
+        let salts = [0, SPLASH_SALT, FIRE_SALT, REACT_SALT, GAS_SALT, POWDER_SALT, EXPLODE_SALT, RIGID_SALT];
+        assert_eq!(salts.iter().collect::<HashSet<_>>().len(), salts.len());
+    }
+}
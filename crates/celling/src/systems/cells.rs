@@ -7,12 +7,12 @@
 // println!("Running get_next_po_liquid() took {}", elapsed_time.as_nanos());
 
 use bevy::prelude::*;
-use bevy::utils::hashbrown::HashMap;
+use rand::Rng;
 
 use crate::components::*;
-use crate::prelude::{Density, CellDir, PoInfo, Silent};
 use crate::res::*;
 use crate::comm::*;
+use super::{fire, gas, heat, powder, pressure, reactions};
 
 const NEIGHBOR_TOP_LEFT: Po = Po::new(-1*PIXEL_SIZE, 1*PIXEL_SIZE);
 const NEIGHBOR_TOP: Po = Po::new(0*PIXEL_SIZE, 1*PIXEL_SIZE);
@@ -22,264 +22,543 @@ const NEIGHBOR_RIGHT: Po = Po::new(1*PIXEL_SIZE, 0*PIXEL_SIZE);
 const NEIGHBOR_BOTTOM_LEFT: Po = Po::new(-1*PIXEL_SIZE, -1*PIXEL_SIZE);
 const NEIGHBOR_BOTTOM: Po = Po::new(0*PIXEL_SIZE, -1*PIXEL_SIZE);
 const NEIGHBOR_BOTTOM_RIGHT: Po = Po::new(1*PIXEL_SIZE, -1*PIXEL_SIZE);
+const GAS_DIFFUSE_DIRS: [Po; 8] = [
+    NEIGHBOR_TOP_LEFT, NEIGHBOR_TOP, NEIGHBOR_TOP_RIGHT,
+    NEIGHBOR_LEFT, NEIGHBOR_RIGHT,
+    NEIGHBOR_BOTTOM_LEFT, NEIGHBOR_BOTTOM, NEIGHBOR_BOTTOM_RIGHT,
+];
 
 
-// 拆分handle与handle_update_map的原因是：
-// 在handle中将逻辑计算并行化，在handle_update_map中统一修改位置信息
-// 注意：这意味着同一帧中一个坐标属于一个像素
-use bevy::core::FrameCount;
+// 按2x2棋盘格分4批更新区块，同一批的区块互不相邻，
+// 每个区块在自己的窗口里直接读写，批内并行
+use bevy::tasks::ComputeTaskPool;
+// 冲突失败的格子在同一帧内重试的次数
+const MAX_RETRY: usize = 2;
+// 粉末和液体每帧累加的重力，速度单位是格子/帧
+pub(crate) const GRAVITY: f32 = 0.3;
+// 一帧最多走的格子数，不能超出窗口
+const MAX_CELL_SPEED: f32 = 8.;
+const _: () = assert!((MAX_CELL_SPEED as i32) < WINDOW_REACH);
+// 液体落地时竖直速度转成水平速度的比例
+const SPLASH_RATE: f32 = 0.6;
+// 贴着地面时每帧保留的水平速度
+const GROUND_FRICTION: f32 = 0.8;
+// 气体没有重力，被炸飞之类得到的速度每帧保留这个比例
+const GAS_DRAG: f32 = 0.7;
+// 液体落地时竖直速度超过这个值才会溅出粒子
+const SPLASH_EJECT_SPEED: f32 = 4.;
+// 落地的液体溅出粒子的概率
+const SPLASH_EJECT_CHANCE: f64 = 0.15;
+// 溅出的粒子向上的速度占落地速度的比例
+const SPLASH_BOUNCE: f32 = 0.4;
 pub fn handle(
-    query: Query<Entity, (With<Cell>, Without<Silent>)>,
-    query2: Query<(&Transform, &Cell, &Density, &CellDir)>,
-    cells_map: Res<CellsMap>,
-    par_commands: ParallelCommands,
-    _count: Res<FrameCount>,
+    mut cells_map: ResMut<CellsMap>,
+    tick: Res<SimTick>,
+    sim_rng: Res<SimRng>,
+    reg: Res<MaterialRegistry>,
+    mut ignited: EventWriter<CellIgnited>,
+    mut reacted: EventWriter<CellReacted>,
 )
 {
-    query.par_iter().for_each(|e| {
-        if let Ok((t, c, d, cd)) = query2.get(e) {
-            let old_p = Po {x: t.translation.x as i32, y: t.translation.y as i32};
-            if let (Some(new_p), new_cd_op) = match c {
-                Cell::Sand => {
-                    (get_next_po_sand(&old_p, &c, d, cd, &cells_map), None)
-                }
-                Cell::Liquip => {
-                    let next_p = get_next_po_liquid(&old_p, &c, d, cd, &cells_map, &query2);
-                    if let Some(new_p) = next_p {
-                        (next_p, Some(CellDir::calc_dir(&old_p, &new_p)))
-                    } else {
-                        (next_p, None)
-                    }
-                    
-                }
-                Cell::Gas => {
-                    let next_p: Option<IVec2> = get_next_po_gas(&old_p, &c, d, cd, &cells_map, &query2);
-                    if let Some(new_p) = next_p {
-                        (next_p, Some(CellDir::calc_dir(&old_p, &new_p)))
-                    } else {
-                        (None, None)
-                    }
-                }
-                _ => (None, None)
-            } {
-                par_commands.command_scope(|mut cmds| {
-                    // println!("{}--- handle cell update {:?}_{:?}:{}->{}", count.0, c, e, old_p, new_p);
-                    let mut ecmds = cmds.entity(e);
-                    
-                    if let Some(new_cd) = new_cd_op {
-                        ecmds.insert(new_cd).insert(PoInfo::new(e, new_p, old_p));
-                    } else {
-                        ecmds.insert(PoInfo::new(e, new_p, old_p));
-                    }
+    let tick = tick.0;
+    let sim_rng = *sim_rng;
+    let reg = &*reg;
+    cells_map.prepare_neighbors();
+    // 批次顺序也按帧号正反交替，区块接缝处不会总是同一边先走
+    let mut passes: [usize; CHECKER_PASSES] = std::array::from_fn(|i| i);
+    if tick % 2 == 1 {
+        passes.reverse();
+    }
+    for pass in passes {
+        let mut windows = cells_map.windows(pass);
+        ComputeTaskPool::get().scope(|s| {
+            for w in windows.iter_mut() {
+                s.spawn(async move {
+                    update_chunk(w, tick, &sim_rng, reg);
                 });
             }
-        } else {
-            println!("[ALARM] handle cell update e={:?} not exists.", e);
-        }
+        });
+        let deltas = windows.into_iter().map(|w| w.finish()).collect();
+        cells_map.apply_window_deltas(deltas);
+    }
+    cells_map.apply_flows(tick, reg);
+    cells_map.release_empty();
+    ignited.send_batch(cells_map.take_ignited());
+    reacted.send_batch(cells_map.take_reacted());
+}
+
+fn update_chunk(w: &mut ChunkWindow, tick: u32, sim_rng: &SimRng, reg: &MaterialRegistry) {
+    let mut order: Vec<(Po, (i32, f32))> = w.center_cells().into_iter()
+        .filter_map(|p| {
+            let c = w.get(&p)?;
+            Some((p, (reg.get(c.mat).density, c.v.0.hypot(c.v.1))))
+        })
+        .collect();
+    // 重的、快的先走；同优先级按坐标从下到上，左右方向按帧号交替，保证结果确定又不偏向一边
+    let rightward = tick % 2 == 0;
+    order.sort_by(|(pa, a), (pb, b)| {
+        b.0.cmp(&a.0)
+            .then(b.1.total_cmp(&a.1))
+            .then(pa.y.cmp(&pb.y))
+            .then(if rightward { pa.x.cmp(&pb.x) } else { pb.x.cmp(&pa.x) })
     });
+
+    // 按顺序在当前地图上计算意图并立即执行，下面的格子先走开，上面的才能跟着落下
+    let mut losers = Vec::new();
+    for (p, _) in order {
+        if let Some(MoveResult::Blocked) = step(&p, w, tick, sim_rng, reg) {
+            losers.push(p);
+        }
+    }
+
+    // 失败的格子按当前地图重新计算意图
+    for _ in 0..MAX_RETRY {
+        if losers.is_empty() {
+            break;
+        }
+        let mut next_losers = Vec::new();
+        for lp in losers.drain(..) {
+            if let Some(MoveResult::Blocked) = step(&lp, w, tick, sim_rng, reg) {
+                next_losers.push(lp);
+            }
+        }
+        losers = next_losers;
+    }
+    reactions::react(w, reg, tick, sim_rng);
+    pressure::relax(w, reg);
+    heat::conduct(w, reg);
+    heat::transition(w, reg);
+    fire::burn(w, reg, tick, sim_rng);
+    gas::dissipate(w, reg, tick, sim_rng);
+    powder::settle(w, reg, tick, sim_rng);
+    w.rest_center(tick);
 }
 
-pub fn handle_update_map(
-    mut cmds: Commands,
-    query: Query<&PoInfo, With<Cell>>,
-    mut cells_map: ResMut<CellsMap>,
-) {
-    let mut updated_map = HashMap::<Po, bool>::new();
-    for po_info in query.iter() {
-        // info!("{:?}", po_info);
-        let cp = po_info.cp;
-        let lp = po_info.lp;
-        let e = po_info.e;
-        // println!("--- handle_update_map curpos={},lastpos={},entity={:?}", cp, lp, e);
-        // 如果上一个位置是自己，则要删除，不是自己则说明被占用
-        if cp != lp {
-            if cells_map.get(&lp) == Some(&e) {
-                cells_map.del(&lp);
+// 计算一个格子的意图并执行，液体重重落地时有概率溅出一个粒子
+fn step(p: &Po, w: &mut ChunkWindow, tick: u32, sim_rng: &SimRng, reg: &MaterialRegistry) -> Option<MoveResult> {
+    let fall = w.get(p)?.v.1;
+    let po_info = get_next_move(p, w, tick, sim_rng, reg)?;
+    let result = apply_move(&po_info, w, tick);
+    let Some(nv) = po_info.v else {
+        return Some(result);
+    };
+    let cp = po_info.cp;
+    if matches!(result, MoveResult::Done)
+        && fall <= -SPLASH_EJECT_SPEED
+        && nv.1 == 0.
+        && w.get(&cp).is_some_and(|c| reg.get(c.mat).class == MoveClass::Liquid)
+        && sim_rng.cell_rng_salted(tick, &cp, SPLASH_SALT).gen_bool(SPLASH_EJECT_CHANCE)
+    {
+        w.eject(&cp, Vec2::new(nv.0, -fall * SPLASH_BOUNCE));
+    }
+    Some(result)
+}
+
+fn get_next_move(old_p: &Po, map: &impl CellGrid, tick: u32, sim_rng: &SimRng, reg: &MaterialRegistry) -> Option<PoInfo> {
+    let cell = map.get(old_p)?;
+    // 本帧已经移动过，或者在休眠
+    if cell.tick == tick || cell.is_sleeping() {
+        return None;
+    }
+    let (m, cd) = (reg.get(cell.mat), &cell.cd);
+    let mut rng = sim_rng.cell_rng(tick, old_p);
+    let falls = matches!(m.class, MoveClass::Powder | MoveClass::Liquid);
+    let v = if falls {
+        CellVelocity(
+            cell.v.0.clamp(-MAX_CELL_SPEED, MAX_CELL_SPEED),
+            (cell.v.1 - GRAVITY).clamp(-MAX_CELL_SPEED, MAX_CELL_SPEED),
+        )
+    } else {
+        CellVelocity(
+            cell.v.0.clamp(-MAX_CELL_SPEED, MAX_CELL_SPEED),
+            cell.v.1.clamp(-MAX_CELL_SPEED, MAX_CELL_SPEED),
+        )
+    };
+    // 速度够走一格以上时沿速度方向走，否则按材质规则走一格
+    if falls || m.class == MoveClass::Gas {
+        if let Some((new_p, nv)) = get_next_po_velocity(old_p, &v, m, cd, map, &mut rng) {
+            let (new_cd, nv) = match m.class {
+                MoveClass::Liquid => (Some(CellDir::calc_dir(old_p, &new_p)), nv),
+                MoveClass::Gas => (Some(CellDir::calc_dir(old_p, &new_p)), CellVelocity(nv.0 * GAS_DRAG, nv.1 * GAS_DRAG)),
+                _ => (None, nv),
+            };
+            return Some(PoInfo::new(new_p, *old_p, new_cd, Some(nv)));
+        }
+    }
+    let (new_p, new_cd) = match m.class {
+        MoveClass::Powder => {
+            (get_next_po_sand(old_p, m, cd, cell.sliding, map, reg, &mut rng)?, None)
+        }
+        MoveClass::Liquid => {
+            let new_p = get_next_po_liquid(old_p, m, cd, map, reg, &mut rng)?;
+            (new_p, Some(CellDir::calc_dir(old_p, &new_p)))
+        }
+        MoveClass::Gas => {
+            let new_p = get_next_po_gas(old_p, m, cd, map, reg, &mut rng)?;
+            (new_p, Some(CellDir::calc_dir(old_p, &new_p)))
+        }
+        MoveClass::Static => return None
+    };
+    // 往下掉的保留累积的速度，其余的竖直速度清零
+    let nv = if !falls {
+        None
+    } else if new_p.y < old_p.y {
+        Some(v)
+    } else {
+        Some(CellVelocity(v.0 * GROUND_FRICTION, 0.))
+    };
+    Some(PoInfo::new(new_p, *old_p, new_cd, nv))
+}
+
+// 沿Bresenham路径走到第一个被挡住的格子前，撞上时损失撞击方向的速度
+fn get_next_po_velocity(
+    p: &Po,
+    v: &CellVelocity,
+    m: &Material,
+    cd: &CellDir,
+    map: &impl CellGrid,
+    rng: &mut CellRng,
+) -> Option<(Po, CellVelocity)> {
+    let d = Po::new(v.0.round() as i32, v.1.round() as i32);
+    if d == Po::ZERO {
+        return None;
+    }
+    let mut last = *p;
+    let mut hit = None;
+    for q in line_between(p, &(*p + d * PIXEL_SIZE)) {
+        if map.get(&q).is_some() {
+            hit = Some(q);
+            break;
+        }
+        last = q;
+    }
+    if last == *p {
+        return None;
+    }
+    let mut nv = *v;
+    if let Some(h) = hit {
+        if h.y != last.y {
+            // 液体落地溅开，越粘溅得越近
+            if m.class == MoveClass::Liquid {
+                let dir = if nv.0 != 0. {
+                    nv.0.signum()
+                } else {
+                    match cd {
+                        CellDir::Left => -1.,
+                        CellDir::Right => 1.,
+                        CellDir::None => if rng.gen_bool(0.5) { 1. } else { -1. },
+                    }
+                };
+                nv.0 = (nv.0 + dir * nv.1.abs() * SPLASH_RATE * (1. - m.viscosity.clamp(0., 1.))).clamp(-MAX_CELL_SPEED, MAX_CELL_SPEED);
             }
+            nv.1 = 0.;
         }
-        if !updated_map.contains_key(&cp) {
-            cells_map.add(&cp, &e);
-            cmds.entity(e).insert(Transform::from_xyz(
-                cp.x as f32,
-                cp.y as f32,
-                0.,
-            )).remove::<PoInfo>();
-            updated_map.insert(cp, true);
+        if h.x != last.x {
+            nv.0 = 0.;
         }
     }
+    if map.get(&last.get_neighbor(NEIGHBOR_BOTTOM)).is_some() {
+        nv.0 *= GROUND_FRICTION;
+    }
+    Some((last, nv))
 }
 
+// 裁决结果
+enum MoveResult {
+    Done,
+    // 终点本帧已被别的格子占用，可以重试
+    Blocked,
+    // 起点的格子已不是发起意图的格子
+    Stale,
+}
+
+// 移动过(或被换位)的格子记下帧号，保证每个格子每帧只动一次
+fn apply_move(po_info: &PoInfo, map: &mut impl CellGrid, tick: u32) -> MoveResult {
+    let cp = po_info.cp;
+    let lp = po_info.lp;
+    let Some(c) = map.get(&lp) else {
+        return MoveResult::Stale;
+    };
+    if c.tick == tick {
+        return MoveResult::Stale;
+    }
+    if map.get(&cp).is_some_and(|nc| nc.tick == tick) {
+        return MoveResult::Blocked;
+    }
+    // 多格移动时路径上可能已经有本帧先到的格子
+    if (cp - lp).abs().max_element() > PIXEL_SIZE
+        && line_between(&lp, &cp).iter().any(|q| map.get(q).is_some()) {
+        return MoveResult::Blocked;
+    }
+    // 终点的格子本帧没动过，说明就是计算意图时看到的那个，直接交换
+    let cd = po_info.cd.unwrap_or(c.cd);
+    let v = po_info.v.unwrap_or(c.v);
+    // 压强跟着深度变，往下走一格压强大一格
+    let dy = ((lp.y - cp.y) / PIXEL_SIZE) as f32;
+    map.swap(&lp, &cp);
+    if let Some(c) = map.get_mut(&cp) {
+        c.cd = cd;
+        c.v = v;
+        c.tick = tick;
+        c.pressure = (c.pressure + dy).max(0.);
+    }
+    if let Some(c) = map.get_mut(&lp) {
+        c.tick = tick;
+        c.pressure = (c.pressure - dy).max(0.);
+    }
+    MoveResult::Done
+}
+
+#[cfg(feature = "debug")]
 pub fn handle_debug(
-    // mut cmds: Commands,
-    // cells_map: ResMut<CellsMap>,
-    // query: Query<Entity, With<DebugMask>>,
+    mut cmds: Commands,
+    cells_map: Res<CellsMap>,
+    query: Query<Entity, With<DebugMask>>,
 ) {
-    // for e in query.iter() {
-    //     cmds.entity(e).despawn();
-    // }
-    // cells_map.show_debug_info_all(&mut cmds);
+    for e in query.iter() {
+        cmds.entity(e).despawn();
+    }
+    cells_map.show_debug_info_all(&mut cmds);
+}
+
+#[cfg(not(feature = "debug"))]
+pub fn handle_debug(
+) {
+}
+
+// 能否移动到p：空位，或者沿运动方向可以挤开的流体
+// 往下落的只能挤开更轻的，往上升的只能挤开更重的，挤开的概率受对方粘度影响
+fn can_displace(
+    p: &Po,
+    m: &Material,
+    rising: bool,
+    map: &impl CellGrid,
+    reg: &MaterialRegistry,
+    rng: &mut CellRng,
+) -> bool {
+    let Some(nc) = map.get(p) else {
+        return true;
+    };
+    let nm = reg.get(nc.mat);
+    if !nm.is_fluid() || nm.id == m.id {
+        return false;
+    }
+    let lighter = nm.density < m.density;
+    if lighter == rising || nm.density == m.density {
+        return false;
+    }
+    nm.viscosity <= 0. || !rng.gen_bool(nm.viscosity.min(1.) as f64)
+}
+
+// 先试正方向，两个斜方向的先后每个格子每帧随机，堆起来才不会往一边偏
+fn diagonals_after(first: Po, left: Po, right: Po, rng: &mut CellRng) -> [Po; 3] {
+    if rng.gen_bool(0.5) {
+        [first, left, right]
+    } else {
+        [first, right, left]
+    }
 }
 
 fn get_next_po_sand(
-    p: &Po, _c: &Cell, _d: &Density, _cd: &CellDir, map: &CellsMap
+    p: &Po, m: &Material, _cd: &CellDir, sliding: bool, map: &impl CellGrid, reg: &MaterialRegistry, rng: &mut CellRng,
 ) -> Option<Po> {
-    let c = p.get_neighbor(NEIGHBOR_BOTTOM);
-    if map.get(&c) == None {
-        return Some(c)
-    }
-    let c = p.get_neighbor(NEIGHBOR_BOTTOM_LEFT);
-    if map.get(&c) == None {
-        return Some(c)
-    } 
-    let c = p.get_neighbor(NEIGHBOR_BOTTOM_RIGHT);
-    if map.get(&c) == None {
-        return Some(c)
+    // 停住的颗粒不往斜下方滑
+    let k = if sliding { 3 } else { 1 };
+    for n in diagonals_after(NEIGHBOR_BOTTOM, NEIGHBOR_BOTTOM_LEFT, NEIGHBOR_BOTTOM_RIGHT, rng).into_iter().take(k) {
+        let c = p.get_neighbor(n);
+        if can_displace(&c, m, false, map, reg, rng) {
+            return Some(c)
+        }
     }
     None
 }
 
 fn get_next_po_liquid(
     p: &Po, 
-    _c: &Cell, 
-    d: &Density, 
+    m: &Material, 
     cd: &CellDir, 
-    map: &CellsMap, 
-    query2: &Query<(&Transform, &Cell, &Density, &CellDir)>
+    map: &impl CellGrid,
+    reg: &MaterialRegistry,
+    rng: &mut CellRng,
 ) -> Option<Po> {
-    let c = p.get_neighbor(NEIGHBOR_BOTTOM);
-    if let Some(bottom_e) = map.get(&c) {
-        let nc = query2.component::<Cell>(*bottom_e);
-        let nd = query2.component::<Density>(*bottom_e);
-        if *nc == Cell::Liquip && d > nd {
-            return Some(c)
-        }
-    } else {
-        return Some(c)
+    // 粘度越大这一帧越可能不动，高速下落走的是速度那条路，不受影响
+    if m.viscosity > 0. && rng.gen_bool(m.viscosity.min(1.) as f64) {
+        return None
     }
-    let c = p.get_neighbor(NEIGHBOR_TOP);
-    if let Some(neighbor_e) = map.get(&c) {
-        let nd = query2.component::<Density>(*neighbor_e);
-        if d < nd {
+    for n in diagonals_after(NEIGHBOR_BOTTOM, NEIGHBOR_BOTTOM_LEFT, NEIGHBOR_BOTTOM_RIGHT, rng) {
+        let c = p.get_neighbor(n);
+        if can_displace(&c, m, false, map, reg, rng) {
             return Some(c)
         }
     }
-    let c = p.get_neighbor(NEIGHBOR_BOTTOM_LEFT);
-    if map.get(&c) == None {
-        return Some(c)
-    } 
-    let c = p.get_neighbor(NEIGHBOR_BOTTOM_RIGHT);
-    if map.get(&c) == None {
-        return Some(c)
-    }
+    let n = (m.liquid.dispersion as i32).min(MAX_CELL_SPEED as i32);
     let c1 = p.get_neighbor(NEIGHBOR_LEFT);
     let c2 = p.get_neighbor(NEIGHBOR_RIGHT);
     match (map.get(&c1), map.get(&c2)) {
         (Some(_), Some(_)) => {
-            return None
+            None
         }
+        // 只有旁边是液体时才往空的一边流
         (Some(ne1), None) => {
-            if query2.component::<Cell>(*ne1) == &Cell::Liquip {
-                match CellDir::new2([CellDir::None, CellDir::Right]) {
-                    CellDir::Right => {
-                        return Some(c2)
-                    }
-                    _ => {
-                        return None
-                    }
-                }
+            if reg.get(ne1.mat).class == MoveClass::Liquid {
+                slide(p, 1, n, NEIGHBOR_BOTTOM, map)
+            } else {
+                None
             }
         }
         (None, Some(ne2)) => {
-            if query2.component::<Cell>(*ne2) == &Cell::Liquip {
-                match CellDir::new2([CellDir::None, CellDir::Left]) {
-                    CellDir::Left => {
-                        return Some(c1)
-                    }
-                    _ => {
-                        return None
-                    }
-                }
+            if reg.get(ne2.mat).class == MoveClass::Liquid {
+                slide(p, -1, n, NEIGHBOR_BOTTOM, map)
+            } else {
+                None
             }
         }
         (None, None) => {
             match cd {
                 CellDir::Left => {
-                    return Some(c1)
+                    slide(p, -1, n, NEIGHBOR_BOTTOM, map)
                 }
                 CellDir::Right => {
-                    return Some(c2)
+                    slide(p, 1, n, NEIGHBOR_BOTTOM, map)
                 }
                 _ => {
-                    return None
+                    None
                 }
             }
         }
     }
-    None
 }
 
 fn get_next_po_gas(
     p: &Po, 
-    _c: &Cell, 
-    d: &Density, 
+    m: &Material, 
     cd: &CellDir, 
-    map: &CellsMap,
-    query2: &Query<(&Transform, &Cell, &Density, &CellDir)>
+    map: &impl CellGrid,
+    reg: &MaterialRegistry,
+    rng: &mut CellRng,
 ) -> Option<Po> {
-    let c = p.get_neighbor(NEIGHBOR_TOP);
-    if let Some(bottom_e) = map.get(&c) {
-        let nc = query2.component::<Cell>(*bottom_e);
-        let nd = query2.component::<Density>(*bottom_e);
-        if *nc == Cell::Liquip && d > nd {
+    // 随机乱跑
+    let gas = &m.gas;
+    if gas.diffusion > 0. && rng.gen_bool(gas.diffusion.min(1.) as f64) {
+        let c = p.get_neighbor(GAS_DIFFUSE_DIRS[rng.gen_range(0..GAS_DIFFUSE_DIRS.len())]);
+        if map.get(&c).is_none() {
             return Some(c)
         }
-    } else {
-        return Some(c)
     }
-    let c = p.get_neighbor(NEIGHBOR_BOTTOM);
-    if let Some(neighbor_e) = map.get(&c) {
-        let nd = query2.component::<Density>(*neighbor_e);
-        if d < nd {
+    for n in diagonals_after(NEIGHBOR_TOP, NEIGHBOR_TOP_LEFT, NEIGHBOR_TOP_RIGHT, rng) {
+        let c = p.get_neighbor(n);
+        if can_displace(&c, m, true, map, reg, rng) {
             return Some(c)
         }
     }
-    let c = p.get_neighbor(NEIGHBOR_TOP_LEFT);
-    if map.get(&c) == None {
-        return Some(c)
-    } 
-    let c = p.get_neighbor(NEIGHBOR_TOP_RIGHT);
-    if map.get(&c) == None {
-        return Some(c)
+    // 上面堵住了就沿原来的方向横着散开，走不动就掉头
+    let dir = match cd {
+        CellDir::Left => -1,
+        CellDir::Right => 1,
+        CellDir::None => if rng.gen_bool(0.5) { 1 } else { -1 },
+    };
+    let spread = (gas.spread as i32).min(MAX_CELL_SPEED as i32);
+    slide(p, dir, spread, NEIGHBOR_TOP, map).or_else(|| slide(p, -dir, spread, NEIGHBOR_TOP, map))
+}
+
+// 沿水平方向dir最多走n格，返回能走到的最远的空位，经过的格子open方向是空的就停在那里
+fn slide(p: &Po, dir: i32, n: i32, open: Po, map: &impl CellGrid) -> Option<Po> {
+    let mut last = None;
+    for i in 1..=n {
+        let c = *p + Po::new(dir * i * PIXEL_SIZE, 0);
+        if map.get(&c).is_some() {
+            break;
+        }
+        last = Some(c);
+        // 液体走到边缘下面是空的、气体走到上面是空的就停下，下一帧从这里掉下去或者升上去
+        if map.get(&c.get_neighbor(open)).is_none() {
+            break;
+        }
     }
-    let c1 = p.get_neighbor(NEIGHBOR_LEFT);
-    let c2 = p.get_neighbor(NEIGHBOR_RIGHT);
-    match (map.get(&c1), map.get(&c2)) {
-        (Some(_), Some(_)) => {
-            return None
+    last
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::ecs::schedule::Schedule;
+    use bevy::tasks::TaskPool;
+
+    use super::*;
+    use crate::systems::{particles, tick};
+
+    fn put(map: &mut CellsMap, reg: &MaterialRegistry, name: &str, x: i32, y: i32) {
+        create_cell(map, reg, CellBundle {mat: reg.id(name).unwrap(), cd: CellDir::None}, x * PIXEL_SIZE, y * PIXEL_SIZE, None);
+    }
+
+    // 按SimSchedule的顺序跑ticks帧
+    fn simulate(map: CellsMap, reg: MaterialRegistry, seed: u64, ticks: usize) -> CellsMap {
+        ComputeTaskPool::get_or_init(TaskPool::default);
+        let mut world = World::new();
+        world.insert_resource(map);
+        world.insert_resource(reg);
+        world.insert_resource(SimRng::new(seed));
+        world.init_resource::<SimTick>();
+        world.init_resource::<Particles>();
+        world.init_resource::<Events<CellIgnited>>();
+        world.init_resource::<Events<CellReacted>>();
+        let mut schedule = Schedule::default();
+        schedule.add_systems((tick::advance_tick, handle, particles::update).chain());
+        for _ in 0..ticks {
+            schedule.run(&mut world);
         }
-        (Some(ne1), None) => {
-            if query2.component::<Cell>(*ne1) == &Cell::Gas {
-                return Some(c2)
+        world.remove_resource::<CellsMap>().unwrap()
+    }
+
+    fn run(seed: u64, ticks: usize) -> String {
+        let reg = MaterialRegistry::default();
+        let mut map = CellsMap::default();
+        // 跨过x=0和y=64的区块接缝
+        for x in -40..40 {
+            put(&mut map, &reg, "stone", x, 0);
+        }
+        for y in 40..90 {
+            for x in -6..6 {
+                put(&mut map, &reg, if x < 0 { "sand" } else { "water" }, x, y);
             }
         }
-        (None, Some(ne2)) => {
-            if query2.component::<Cell>(*ne2) == &Cell::Gas {
-                return Some(c1)
+        let map = simulate(map, reg, seed, ticks);
+        let mut cells: Vec<(Po, CellData)> = map.iter().map(|(p, c)| (p, *c)).collect();
+        cells.sort_unstable_by_key(|(p, _)| (p.y, p.x));
+        format!("{:?}", cells)
+    }
+
+    // 相同种子跑两遍，格子要一模一样
+    #[test]
+    fn same_seed_same_grid() {
+        assert_eq!(run(7, 120), run(7, 120));
+    }
+
+    // U形管只往左边灌水，压强推着水从底下的通道流过去，最后两边液面一样高
+    #[test]
+    fn u_tube_levels_out() {
+        let reg = MaterialRegistry::default();
+        let mut map = CellsMap::default();
+        for x in -8..=8 {
+            put(&mut map, &reg, "stone", x, 0);
+        }
+        for y in 1..21 {
+            put(&mut map, &reg, "stone", -8, y);
+            put(&mut map, &reg, "stone", 8, y);
+            if y >= 3 {
+                put(&mut map, &reg, "stone", 0, y);
             }
         }
-        (None, None) => {
-            match cd {
-                CellDir::Left => {
-                    return Some(c1)
-                }
-                CellDir::Right => {
-                    return Some(c2)
-                }
-                _ => {
-                    return None
-                }
+        for y in 1..17 {
+            for x in -7..0 {
+                put(&mut map, &reg, "water", x, y);
             }
         }
+        let map = simulate(map, reg, 1, 800);
+        let level = |xs: std::ops::Range<i32>| (1..21)
+            .filter(|y| xs.clone().any(|x| map.get(&Po::new(x * PIXEL_SIZE, y * PIXEL_SIZE)).is_some()))
+            .max()
+            .unwrap();
+        let (left, right) = (level(-7..0), level(1..8));
+        assert!((left - right).abs() <= 1, "left {} right {}", left, right);
     }
-    None
-}
\ No newline at end of file
+}
@@ -0,0 +1,117 @@
+use bevy::prelude::*;
+use bevy_rapier2d::prelude::*;
+use rand::Rng;
+
+use crate::comm::*;
+use crate::components::*;
+use crate::res::*;
+
+// 威力为1时格子被炸飞的速度，格子/帧
+const EXPLODE_FLING: f32 = 1.5;
+// 威力为1时刚体受到的冲量
+const EXPLODE_IMPULSE: f32 = 2000.;
+
+// 离中心dist格处的威力
+fn power_at(power: f32, dist: f32, radius: i32) -> f32 {
+    power * (1. - dist / (radius as f32 + 1.)).max(0.)
+}
+
+// 给p处的格子加上速度v，不离开网格
+fn push(map: &mut CellsMap, p: &Po, v: Vec2) {
+    if let Some(c) = map.get_mut_quiet(p) {
+        c.v = CellVelocity(c.v.0 + v.x, c.v.1 + v.y);
+        c.sliding = true;
+    }
+    map.wake(p);
+}
+
+pub fn handle(
+    mut events: EventReader<Explode>,
+    mut map: ResMut<CellsMap>,
+    mut bodies: Query<(Entity, &RigidBody, &GlobalTransform, Option<&mut ExternalImpulse>)>,
+    mut cmds: Commands,
+    reg: Res<MaterialRegistry>,
+    sim_rng: Res<SimRng>,
+    tick: Res<SimTick>,
+) {
+    for ev in events.read() {
+        let (x, y) = get_cell_create_pos(ev.center.x, ev.center.y);
+        let center = Po::new(x, y);
+        let radius = ev.radius.max(0);
+        for dy in -radius..=radius {
+            for dx in -radius..=radius {
+                if dx * dx + dy * dy > radius * radius {
+                    continue;
+                }
+                let p = center + Po::new(dx, dy) * PIXEL_SIZE;
+                let Some(c) = map.get(&p) else {
+                    continue;
+                };
+                let m = reg.get(c.mat);
+                if m.has_flag(MaterialFlag::Indestructible) {
+                    continue;
+                }
+                let dist = ((dx * dx + dy * dy) as f32).sqrt();
+                let power = power_at(ev.power, dist, radius);
+                let mut rng = sim_rng.cell_rng_salted(tick.0, &p, EXPLODE_SALT);
+                let dir = if dist > 0. {
+                    Vec2::new(dx as f32, dy as f32) / dist
+                } else {
+                    Vec2::from_angle(rng.gen_range(0.0..std::f32::consts::TAU))
+                };
+                let v = dir * power * EXPLODE_FLING * rng.gen_range(0.8..1.2);
+                // 威力不到硬度就炸不动，松散的格子只是被推一下，还留在网格里
+                if power < m.hardness {
+                    if m.is_movable() {
+                        push(&mut map, &p, v);
+                    }
+                    continue;
+                }
+                // 固体炸碎变成碎片，没有碎片的直接消失
+                let m = if !m.is_movable() {
+                    let Some(debris) = m.debris() else {
+                        map.del(&p);
+                        continue;
+                    };
+                    if let Some(c) = map.get_mut(&p) {
+                        c.switch_material(reg.get(debris), &p);
+                    }
+                    reg.get(debris)
+                } else {
+                    m
+                };
+                if !m.is_movable() {
+                    continue;
+                }
+                // 粉末和液体炸成粒子飞出去，落地再变回格子，气体顺着速度散开
+                if matches!(m.class, MoveClass::Powder | MoveClass::Liquid) {
+                    map.eject(&p, v);
+                } else {
+                    push(&mut map, &p, v);
+                }
+            }
+        }
+
+        let origin = Vec2::new(center.x as f32, center.y as f32);
+        for (e, body, t, impulse) in bodies.iter_mut() {
+            if *body != RigidBody::Dynamic {
+                continue;
+            }
+            let d = t.translation().truncate() - origin;
+            let dist = d.length() / PIXEL_SIZE_F;
+            if dist > radius as f32 {
+                continue;
+            }
+            let dir = d.try_normalize().unwrap_or(Vec2::Y);
+            let delta = dir * power_at(ev.power, dist, radius) * EXPLODE_IMPULSE;
+            match impulse {
+                Some(mut i) => {
+                    i.impulse += delta;
+                }
+                None => {
+                    cmds.entity(e).insert(ExternalImpulse {impulse: delta, torque_impulse: 0.});
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,77 @@
+use rand::Rng;
+
+use crate::components::*;
+use crate::res::*;
+use crate::comm::*;
+
+const FIRE_SIDES: [Po; 4] = [
+    Po::new(PIXEL_SIZE, 0), Po::new(-PIXEL_SIZE, 0),
+    Po::new(0, PIXEL_SIZE), Po::new(0, -PIXEL_SIZE),
+];
+// 燃烧的格子每帧往上方空位冒火的概率
+const FLAME_RATE: f64 = 0.3;
+
+// 燃烧的格子冒火、点燃邻居，烧完变成产物；
+// 火焰点燃邻居；可燃格子温度够高时自燃
+pub(crate) fn burn(w: &mut ChunkWindow, reg: &MaterialRegistry, tick: u32, sim_rng: &SimRng) {
+    let fire = reg.id("fire");
+    for p in w.center_cells() {
+        let Some(c) = w.get(&p) else {
+            continue;
+        };
+        let m = reg.get(c.mat);
+        let (temp, burning) = (c.temp, c.burn);
+        let mut rng = sim_rng.cell_rng_salted(tick, &p, FIRE_SALT);
+        if let Some(left) = burning {
+            if left <= 1 {
+                match m.burn.as_ref().and_then(|b| b.product()) {
+                    Some(product) => {
+                        if let Some(c) = w.get_mut(&p) {
+                            c.switch_material(reg.get(product), &p);
+                        }
+                        w.wake(&p);
+                    }
+                    None => {
+                        w.del(&p);
+                    }
+                }
+                continue;
+            }
+            let heat = m.burn.as_ref().map_or(temp, |b| b.heat);
+            if let Some(c) = w.get_mut_quiet(&p) {
+                c.burn = Some(left - 1);
+                c.temp = c.temp.max(heat);
+            }
+            w.wake(&p);
+            let top = p + FIRE_SIDES[2];
+            if let Some(fire) = fire {
+                if w.get(&top).is_none() && rng.gen_bool(FLAME_RATE) {
+                    w.add(&top, CellData::spawn(reg.get(fire), &top, CellDir::None, None));
+                }
+            }
+            spread(w, &p, reg, &mut rng);
+        } else if m.has_flag(MaterialFlag::Fire) {
+            w.wake(&p);
+            spread(w, &p, reg, &mut rng);
+        } else if m.burn.as_ref().is_some_and(|b| temp >= b.ignite) {
+            w.ignite(&p, reg);
+        }
+    }
+}
+
+// 按邻居材质的spread概率点燃它们
+fn spread(w: &mut ChunkWindow, p: &Po, reg: &MaterialRegistry, rng: &mut CellRng) {
+    for d in FIRE_SIDES {
+        let q = *p + d;
+        let Some(nc) = w.get(&q) else {
+            continue;
+        };
+        if nc.burn.is_some() {
+            continue;
+        }
+        let chance = reg.get(nc.mat).burn.as_ref().map_or(0., |b| b.spread);
+        if chance > 0. && rng.gen_bool(chance.min(1.) as f64) {
+            w.ignite(&q, reg);
+        }
+    }
+}
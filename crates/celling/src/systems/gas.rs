@@ -0,0 +1,42 @@
+use rand::Rng;
+
+use crate::res::*;
+
+// 最后这部分寿命里逐渐变透明
+const FADE_PART: f32 = 0.25;
+
+// 有寿命的格子到时消失、快到时淡出，气体按dissipation的概率凭空消失
+pub(crate) fn dissipate(w: &mut ChunkWindow, reg: &MaterialRegistry, tick: u32, sim_rng: &SimRng) {
+    for p in w.center_cells() {
+        let Some(c) = w.get(&p) else {
+            continue;
+        };
+        let m = reg.get(c.mat);
+        if m.lifetime.is_none() && m.gas.dissipation <= 0. {
+            continue;
+        }
+        let (age, alpha) = (c.age, c.color[3]);
+        if let Some(lifetime) = m.lifetime {
+            if age >= lifetime {
+                w.del(&p);
+                continue;
+            }
+            let left = (lifetime - age) as f32 / (lifetime as f32 * FADE_PART).max(1.);
+            let a = (m.alpha() as f32 * left.min(1.)) as u8;
+            if a != alpha {
+                if let Some(c) = w.get_mut(&p) {
+                    c.color[3] = a;
+                }
+            }
+        }
+        if m.gas.dissipation > 0. {
+            let mut rng = sim_rng.cell_rng_salted(tick, &p, GAS_SALT);
+            if rng.gen_bool(m.gas.dissipation.min(1.) as f64) {
+                w.del(&p);
+                continue;
+            }
+        }
+        // 有寿命的格子不能休眠，否则永远不会消失
+        w.wake(&p);
+    }
+}
@@ -0,0 +1,101 @@
+use crate::components::*;
+use crate::res::*;
+use crate::comm::*;
+
+// 每对格子只在左下的那个格子处理一次
+const HEAT_PAIRS: [Po; 2] = [Po::new(PIXEL_SIZE, 0), Po::new(0, PIXEL_SIZE)];
+// 由左、下邻居处理的那两对，邻居在别的区块时它可能在休眠
+const HEAT_PAIRS_OWNED: [Po; 2] = [Po::new(-PIXEL_SIZE, 0), Po::new(0, -PIXEL_SIZE)];
+const HEAT_SIDES: [Po; 4] = [
+    Po::new(PIXEL_SIZE, 0), Po::new(-PIXEL_SIZE, 0),
+    Po::new(0, PIXEL_SIZE), Po::new(0, -PIXEL_SIZE),
+];
+// 一对格子每帧最多交换温差的这个比例，4个邻居加起来不超过1才稳定
+const CONDUCTION_RATE: f32 = 0.25;
+// 和空气之间的导热比和格子之间慢得多
+const AIR_CONDUCTIVITY: f32 = 0.02;
+
+// 中心区块里的格子和上、右邻居导热，和空白处的空气散热，热源保持温度
+// 左、下邻居在别的区块且有温差时唤醒它，让那边的区块来处理这一对
+pub(crate) fn conduct(w: &mut ChunkWindow, reg: &MaterialRegistry) {
+    for p in w.center_cells() {
+        wake_seam(w, reg, &p);
+        let Some(c) = w.get(&p) else {
+            continue;
+        };
+        let m = reg.get(c.mat);
+        let mut temp = c.temp;
+        if let Some(t) = m.heat_source {
+            w.add_heat(&p, t - temp);
+            temp = t;
+        } else {
+            let air = HEAT_SIDES.iter()
+                .filter(|d| w.get(&(p + **d)).is_none())
+                .count() as f32;
+            if air > 0. {
+                let delta = (AMBIENT_TEMP - temp) * m.conductivity * AIR_CONDUCTIVITY * air;
+                w.add_heat(&p, delta);
+                temp += delta;
+            }
+        }
+        for d in HEAT_PAIRS {
+            let q = p + d;
+            let Some(nc) = w.get(&q) else {
+                continue;
+            };
+            let nm = reg.get(nc.mat);
+            let k = (m.conductivity + nm.conductivity) / 2. * CONDUCTION_RATE;
+            let flow = (nc.temp - temp) * k;
+            if flow == 0. {
+                continue;
+            }
+            // 热源那一侧不变
+            if m.heat_source.is_none() {
+                w.add_heat(&p, flow);
+                temp += flow;
+            }
+            if nm.heat_source.is_none() {
+                w.add_heat(&q, -flow);
+            }
+        }
+    }
+}
+
+fn wake_seam(w: &mut ChunkWindow, reg: &MaterialRegistry, p: &Po) {
+    let Some(c) = w.get(p) else {
+        return;
+    };
+    let (temp, conductivity) = (c.temp, reg.get(c.mat).conductivity);
+    for d in HEAT_PAIRS_OWNED {
+        let q = *p + d;
+        if po_to_chunk(&q).0 == w.center() {
+            continue;
+        }
+        let Some(nc) = w.get(&q) else {
+            continue;
+        };
+        let k = (conductivity + reg.get(nc.mat).conductivity) / 2. * CONDUCTION_RATE;
+        if nc.is_sleeping() && ((nc.temp - temp) * k).abs() >= HEAT_WAKE {
+            w.wake(&q);
+        }
+    }
+}
+
+// 温度越过阈值的格子原地换材质，温度不变，颜色从新材质里挑
+pub(crate) fn transition(w: &mut ChunkWindow, reg: &MaterialRegistry) {
+    for p in w.center_cells() {
+        let Some(c) = w.get(&p) else {
+            continue;
+        };
+        let temp = c.temp;
+        let Some(into) = reg.get(c.mat).transitions.iter()
+            .find(|t| t.fires(temp))
+            .and_then(|t| t.target()) else {
+            continue;
+        };
+        if let Some(c) = w.get_mut(&p) {
+            c.switch_material(reg.get(into), &p);
+        }
+        w.wake(&p);
+    }
+}
@@ -3,7 +3,10 @@ use std::collections::VecDeque;
 use bevy::prelude::*;
 use bevy::asset::LoadState;
 use bevy::utils::HashMap;
-use crate::{comm::*, CellsMap, components::*, systems::rigids::RigidizeEvent};
+use crate::{comm::*, CellsMap, MaterialRegistry, components::*, systems::rigids::RigidizeEvent};
+
+// 图片精灵的格子用静态材质，刚体停下放回网格后保持原来的形状，不会像沙子一样塌掉
+const SPRITE_MATERIAL: &str = "wood";
 
 fn u8_array_to_i32(bytes: [u8; 4]) -> i32 {
     (((bytes[0] as u32) << 24)
@@ -22,13 +25,13 @@ fn i32_to_u8_array(n: i32) -> [u8; 4] {
 }
 
 pub fn spawn_image_sprite_handle(
-    mut cmds: Commands,
     mut spawn_events: EventReader<SpawnImageSpriteEvent>,
     mut loading_map: Local<LoadingImageMap>,
     mut loading_queue: Local<VecDeque<String>>,
     asset_server: Res<AssetServer>,
     images: Res<Assets<Image>>,
     mut map: ResMut<CellsMap>,
+    reg: Res<MaterialRegistry>,
     mut rigid_events: EventWriter<RigidizeEvent>,
 ) {
     for ev in spawn_events.read() {
@@ -36,8 +39,8 @@ pub fn spawn_image_sprite_handle(
         let path = &ev.path;
         if let Some(loading_image) = loading_map.get_mut(path.to_string()) {
             if loading_image.is_loaded() {
-                do_spawn_image_sprite(&mut cmds, &loading_image.bin_data, &mut map, loading_image.pos);
-                rigid_events.send(RigidizeEvent::new(1920, 1080, 1.));
+                do_spawn_image_sprite(&loading_image.bin_data, &mut map, &reg, loading_image.pos);
+                rigid_events.send(RigidizeEvent::new(1.));
             }
         } else {
             info!("spawn_image_sprite_handle {:?}", path);
@@ -73,8 +76,8 @@ pub fn spawn_image_sprite_handle(
                         }
                     }
                 }
-                do_spawn_image_sprite(&mut cmds, &bin_data, &mut map, loading_image.pos);
-                rigid_events.send(RigidizeEvent::new(1920, 1080, 1.));
+                do_spawn_image_sprite(&bin_data, &mut map, &reg, loading_image.pos);
+                rigid_events.send(RigidizeEvent::new(1.));
                 loading_image.set_loaded(bin_data);
                 loading_queue.remove(index);
             }
@@ -85,25 +88,28 @@ pub fn spawn_image_sprite_handle(
 
 
 fn do_spawn_image_sprite(
-    mut cmds: &mut Commands,
     data: &Vec<u8>,
     map: &mut ResMut<CellsMap>,
+    reg: &MaterialRegistry,
     pos: Po,
 ) {
     info!("do_spawn_image_sprite");
+    let Some(mat) = reg.id(SPRITE_MATERIAL) else {
+        return;
+    };
     for (_i, p) in data.chunks(12).enumerate() {
         // info!("======= {} {:?}", i, p);
         let x = u8_array_to_i32([p[0], p[1], p[2], p[3]]);
         let y = u8_array_to_i32([p[4], p[5], p[6], p[7]]);
         let color = Color::rgba_u8(p[8], p[9], p[10], p[11]);
-        // TODO 读配置加载
         let cell_bundle = CellBundle {
-            c: Cell::Sand,
-            d: Density(1),
+            mat,
             cd: CellDir::None,
         };
-        if let Some(e) = create_cell(&mut cmds, map, cell_bundle, pos.x + x as i32 * PIXEL_SIZE, pos.y + y as i32 * PIXEL_SIZE, color) {
-            cmds.entity(e).insert(RigidMeterial(1.));
+        if let Some(p) = create_cell(map, reg, cell_bundle, pos.x + x as i32 * PIXEL_SIZE, pos.y + y as i32 * PIXEL_SIZE, Some(color)) {
+            if let Some(c) = map.get_mut(&p) {
+                c.rm = Some(RigidMeterial(1.));
+            }
         }
     }
 }
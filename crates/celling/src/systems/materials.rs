@@ -0,0 +1,37 @@
+use bevy::prelude::*;
+
+use crate::res::*;
+
+// 插件配置的材质文件路径
+#[derive(Resource, Default, Clone)]
+pub struct MaterialPaths(pub Vec<String>);
+
+pub fn load_materials(
+    paths: Res<MaterialPaths>,
+    asset_server: Res<AssetServer>,
+    mut handles: ResMut<MaterialSetHandles>,
+) {
+    for path in paths.0.iter() {
+        handles.0.push(asset_server.load(path.clone()));
+    }
+}
+
+// 材质文件加载或修改后合并进材质表，同名材质覆盖
+pub fn register_materials(
+    mut events: EventReader<AssetEvent<MaterialSet>>,
+    sets: Res<Assets<MaterialSet>>,
+    mut reg: ResMut<MaterialRegistry>,
+) {
+    for ev in events.read() {
+        let (AssetEvent::Added { id } | AssetEvent::Modified { id }) = ev else {
+            continue;
+        };
+        let Some(set) = sets.get(*id) else {
+            continue;
+        };
+        for m in set.materials.iter() {
+            let id = reg.register(m.clone());
+            info!("register material {} {:?}", m.name, id);
+        }
+    }
+}
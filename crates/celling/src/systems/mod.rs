@@ -1,3 +1,14 @@
 pub mod cells;
 pub mod rigids;
-pub mod load;
\ No newline at end of file
+pub mod load;
+pub mod materials;
+pub mod render;
+pub mod heat;
+pub mod fire;
+pub mod gas;
+pub mod reactions;
+pub mod pressure;
+pub mod powder;
+pub mod explode;
+pub mod particles;
+pub mod tick;
\ No newline at end of file
@@ -0,0 +1,85 @@
+use bevy::prelude::*;
+
+use crate::comm::*;
+use crate::components::*;
+use crate::res::*;
+use super::cells::GRAVITY;
+
+// 粒子一帧最多走的格子数
+const MAX_PARTICLE_SPEED: f32 = 16.;
+// 飞太久的粒子(掉出世界了)直接丢掉
+const PARTICLE_MAX_AGE: u16 = 600;
+// 落地变回格子时保留的速度
+const LAND_KEEP: f32 = 0.5;
+// 落点被占时往上找空位的格子数
+const LAND_SEARCH: i32 = 8;
+// 连续这么多帧都找不到空位落地就丢掉
+const LAND_MAX_TRIES: u16 = 60;
+
+// 收下本帧被弹出网格的格子，推进所有粒子，撞到格子的落回网格
+pub fn update(
+    mut map: ResMut<CellsMap>,
+    mut particles: ResMut<Particles>,
+    tick: Res<SimTick>,
+) {
+    for (p, v, c) in map.take_ejected() {
+        particles.spawn(&p, v, c);
+    }
+    if particles.is_empty() {
+        return;
+    }
+    let mut flying = particles.take();
+    flying.retain_mut(|pt| {
+        pt.age += 1;
+        if pt.age > PARTICLE_MAX_AGE {
+            return false;
+        }
+        pt.v.y -= GRAVITY;
+        pt.v = pt.v.clamp_length_max(MAX_PARTICLE_SPEED);
+        pt.prev = pt.pos;
+        let from = pt.po();
+        pt.pos += pt.v * PIXEL_SIZE_F;
+        let mut last = from;
+        for q in line_between(&from, &pt.po()) {
+            if map.get(&q).is_some() {
+                if land(&mut map, pt, &last, &q, tick.0) {
+                    return false;
+                }
+                // 附近没有空位，停在撞上之前的位置，下一帧接着掉、再试着落地
+                pt.land_tries += 1;
+                pt.pos = last.as_vec2();
+                pt.v = Vec2::ZERO;
+                return pt.land_tries < LAND_MAX_TRIES;
+            }
+            last = q;
+        }
+        true
+    });
+    particles.restore(flying);
+}
+
+// 在撞上hit之前的last处变回格子，last被占了就往上找空位，找不到返回false
+fn land(map: &mut CellsMap, pt: &Particle, last: &Po, hit: &Po, tick: u32) -> bool {
+    let Some(p) = (0..=LAND_SEARCH)
+        .map(|i| *last + Po::new(0, i * PIXEL_SIZE))
+        .find(|q| map.get(q).is_none()) else {
+        return false;
+    };
+    let mut v = pt.v * LAND_KEEP;
+    // 撞击方向的速度没了
+    if hit.y != last.y {
+        v.y = 0.;
+    }
+    if hit.x != last.x {
+        v.x = 0.;
+    }
+    let mut c = pt.cell;
+    c.v = CellVelocity(v.x, v.y);
+    c.tick = tick;
+    c.idle = 0;
+    c.sliding = true;
+    c.pressure = 0.;
+    c.surface = None;
+    map.add(&p, c);
+    true
+}
@@ -0,0 +1,48 @@
+use rand::Rng;
+
+use crate::res::*;
+use crate::comm::*;
+
+const POWDER_BOTTOM: Po = Po::new(0, -PIXEL_SIZE);
+
+// 悬空的颗粒一直在滑动；有东西垫着的颗粒每帧按friction的概率停住
+// 本帧动过的颗粒(包括落地的)按邻居的inertia把停住的邻居带动起来
+pub(crate) fn settle(w: &mut ChunkWindow, reg: &MaterialRegistry, tick: u32, sim_rng: &SimRng) {
+    for p in w.center_cells() {
+        let Some(c) = w.get(&p) else {
+            continue;
+        };
+        let m = reg.get(c.mat);
+        if m.class != MoveClass::Powder {
+            continue;
+        }
+        let moved = c.tick == tick;
+        let mut rng = sim_rng.cell_rng_salted(tick, &p, POWDER_SALT);
+        let sliding = if w.get(&(p + POWDER_BOTTOM)).is_none() {
+            true
+        } else {
+            c.sliding && !(m.powder.friction > 0. && rng.gen_bool(m.powder.friction.min(1.) as f64))
+        };
+        if let Some(c) = w.get_mut_quiet(&p) {
+            c.sliding = sliding;
+        }
+        if !moved {
+            continue;
+        }
+        for q in p.get_neighbors() {
+            let Some(nc) = w.get(&q) else {
+                continue;
+            };
+            let nm = reg.get(nc.mat);
+            if nm.class != MoveClass::Powder || nc.sliding {
+                continue;
+            }
+            if nm.powder.inertia <= 0. || !rng.gen_bool(nm.powder.inertia.min(1.) as f64) {
+                if let Some(nc) = w.get_mut_quiet(&q) {
+                    nc.sliding = true;
+                }
+                w.wake(&q);
+            }
+        }
+    }
+}
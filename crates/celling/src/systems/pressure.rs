@@ -0,0 +1,71 @@
+use crate::components::*;
+use crate::res::*;
+use crate::comm::*;
+
+// 压强的单位是液柱的格子数，每往下一格加1
+const PRESSURE_SIDES: [Po; 2] = [Po::new(PIXEL_SIZE, 0), Po::new(-PIXEL_SIZE, 0)];
+const PRESSURE_TOP: Po = Po::new(0, PIXEL_SIZE);
+const PRESSURE_BOTTOM: Po = Po::new(0, -PIXEL_SIZE);
+// 每传一格损失的压强，液面降下去以后旧的压强靠它慢慢消掉
+const PRESSURE_DECAY: f32 = 0.02;
+// 液面的压强超过这个值，说明连着更高的液面，从那边挪一格液体过来
+const PRESSURE_FLOW: f32 = 1.;
+// 一帧内压强变化超过这个值就唤醒格子，没平衡前液体不休眠
+const PRESSURE_WAKE: f32 = 0.01;
+
+// 液体格子的压强取各个液体邻居推算值里最大的，上方是空的或气体时自己就是液面，压强为0
+// 连通的液体里最高的液面的压强会传遍整片液体，同时记下是从哪个液面格子传来的
+// 液面低的一边被压着，就记下把那个最高的液面格子挪到自己头上
+pub(crate) fn relax(w: &mut ChunkWindow, reg: &MaterialRegistry) {
+    for p in w.center_cells() {
+        let Some(c) = w.get(&p) else {
+            continue;
+        };
+        if reg.get(c.mat).class != MoveClass::Liquid {
+            continue;
+        }
+        let old = c.pressure;
+        let is_liquid = |nc: &&CellData| reg.get(nc.mat).class == MoveClass::Liquid;
+        // 下面是空的就是在往下掉，不承受压强
+        let falling = w.get(&(p + PRESSURE_BOTTOM))
+            .filter(|nc| reg.get(nc.mat).class != MoveClass::Gas)
+            .is_none();
+        let open = w.get(&(p + PRESSURE_TOP))
+            .filter(|nc| reg.get(nc.mat).class != MoveClass::Gas)
+            .is_none();
+        let (mut new, mut surface) = (f32::MIN, None);
+        if !falling {
+            if open {
+                (new, surface) = (0., Some(p));
+            } else if let Some(nc) = w.get(&(p + PRESSURE_TOP)).filter(is_liquid) {
+                // 顶上压着固体时压强只能从别处传来
+                (new, surface) = (nc.pressure + 1., nc.surface);
+            }
+            if let Some(nc) = w.get(&(p + PRESSURE_BOTTOM)).filter(is_liquid) {
+                if nc.pressure - 1. > new {
+                    (new, surface) = (nc.pressure - 1., nc.surface);
+                }
+            }
+            for d in PRESSURE_SIDES {
+                if let Some(nc) = w.get(&(p + d)).filter(is_liquid) {
+                    if nc.pressure > new {
+                        (new, surface) = (nc.pressure, nc.surface);
+                    }
+                }
+            }
+        }
+        let new = (new - PRESSURE_DECAY).max(0.);
+        if let Some(c) = w.get_mut_quiet(&p) {
+            c.pressure = new;
+            c.surface = surface;
+        }
+        if (new - old).abs() >= PRESSURE_WAKE {
+            w.wake(&p);
+        }
+        if open && new > PRESSURE_FLOW {
+            if let Some(s) = surface.filter(|s| *s != p) {
+                w.record_flow(&s, &(p + PRESSURE_TOP));
+            }
+        }
+    }
+}
@@ -0,0 +1,68 @@
+use rand::Rng;
+
+use crate::res::*;
+use crate::comm::*;
+
+// 按顺序检查的邻居，保证结果确定
+const REACT_SIDES: [Po; 4] = [
+    Po::new(0, -PIXEL_SIZE), Po::new(-PIXEL_SIZE, 0),
+    Po::new(PIXEL_SIZE, 0), Po::new(0, PIXEL_SIZE),
+];
+
+// 移动之后按反应表处理中心区块里的格子，格子按下标顺序、邻居按REACT_SIDES顺序，
+// 每个格子每帧最多参与一次反应，包括在别的区块里作为邻居参与的
+pub(crate) fn react(w: &mut ChunkWindow, reg: &MaterialRegistry, tick: u32, sim_rng: &SimRng) {
+    for p in w.center_cells() {
+        let Some(c) = w.get(&p).filter(|c| c.reacted != tick) else {
+            continue;
+        };
+        let this = c.mat;
+        let m = reg.get(this);
+        if m.reactions.is_empty() {
+            continue;
+        }
+        let mut rng = sim_rng.cell_rng_salted(tick, &p, REACT_SALT);
+        for d in REACT_SIDES {
+            let q = p + d;
+            let Some(that) = w.get(&q).filter(|nc| nc.reacted != tick).map(|nc| nc.mat) else {
+                continue;
+            };
+            let Some(r) = m.reactions.iter()
+                .find(|r| r.is_resolved() && r.with_id() == Some(that)) else {
+                continue;
+            };
+            if r.chance < 1. && !rng.gen_bool(r.chance.max(0.) as f64) {
+                continue;
+            }
+            let into = (r.this_into(this), r.that_into(that));
+            become_material(w, &p, this, into.0, reg, tick);
+            become_material(w, &q, that, into.1, reg, tick);
+            w.record_reacted(CellReacted {p, other: q, from: (this, that), into});
+            break;
+        }
+    }
+}
+
+fn become_material(w: &mut ChunkWindow, p: &Po, from: MaterialId, into: Option<MaterialId>, reg: &MaterialRegistry, tick: u32) {
+    match into {
+        Some(into) if into == from => {
+            if let Some(c) = w.get_mut_quiet(p) {
+                c.reacted = tick;
+            }
+            w.wake(p);
+        }
+        Some(into) => {
+            // 产物至少是它新建时的温度，比如岩浆遇水生成的蒸汽
+            let m = reg.get(into);
+            if let Some(c) = w.get_mut(p) {
+                c.switch_material(m, p);
+                c.temp = c.temp.max(m.temperature);
+                c.reacted = tick;
+            }
+            w.wake(p);
+        }
+        None => {
+            w.del(p);
+        }
+    }
+}
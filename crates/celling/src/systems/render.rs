@@ -0,0 +1,111 @@
+use bevy::prelude::*;
+use bevy::render::render_resource::{Extent3d, TextureDimension, TextureFormat};
+use bevy::render::texture::ImageSampler;
+use bevy::utils::{HashMap, HashSet};
+
+use crate::comm::*;
+use crate::res::*;
+
+// 每个区块一张贴图、一个sprite
+#[derive(Resource, Default)]
+pub struct ChunkSprites {
+    map: HashMap<ChunkPo, (Entity, Handle<Image>)>,
+    // 上一帧画过粒子的区块，粒子飞走后要重画
+    particle_chunks: HashSet<ChunkPo>,
+}
+
+fn create_chunk_image() -> Image {
+    let mut img = Image::new_fill(
+        Extent3d {
+            width: CHUNK_SIZE as u32,
+            height: CHUNK_SIZE as u32,
+            depth_or_array_layers: 1,
+        },
+        TextureDimension::D2,
+        &[0, 0, 0, 0],
+        TextureFormat::Rgba8UnormSrgb,
+    );
+    img.sampler = ImageSampler::nearest();
+    img
+}
+
+// 区块贴图中心的世界坐标，格子坐标是格子中心
+fn chunk_center(cp: &ChunkPo) -> Vec2 {
+    let origin = chunk_to_po(cp, 0);
+    let half = (CHUNK_SIZE * PIXEL_SIZE) as f32 / 2.;
+    Vec2::new(
+        origin.x as f32 - PIXEL_SIZE_HALF_F + half,
+        origin.y as f32 - PIXEL_SIZE_HALF_F + half,
+    )
+}
+
+fn write_pixel(img: &mut Image, i: usize, color: &[u8; 4]) {
+    let lx = i % CHUNK_SIZE as usize;
+    let ly = i / CHUNK_SIZE as usize;
+    // 贴图第0行在上面
+    let row = CHUNK_SIZE as usize - 1 - ly;
+    let offset = (row * CHUNK_SIZE as usize + lx) * 4;
+    img.data[offset..offset + 4].copy_from_slice(color);
+}
+
+fn write_chunk_image(img: &mut Image, chunk: Option<&Chunk>, particles: &[(usize, [u8; 4])]) {
+    img.data.fill(0);
+    for (i, c) in chunk.into_iter().flat_map(|c| c.iter()) {
+        write_pixel(img, i, &c.color);
+    }
+    // 粒子不在网格里，直接画在所在区块的贴图上
+    for (i, color) in particles {
+        write_pixel(img, *i, color);
+    }
+}
+
+// 只重新上传本帧有变化的区块，以及上一帧和这一帧有粒子的区块
+pub fn render_chunks(
+    mut cmds: Commands,
+    mut cells_map: ResMut<CellsMap>,
+    mut sprites: ResMut<ChunkSprites>,
+    mut images: ResMut<Assets<Image>>,
+    particles: Res<Particles>,
+    sim_time: Res<SimTime>,
+) {
+    // 格子按模拟帧对齐画，粒子一帧能飞好几格，按插值后的位置画
+    let alpha = sim_time.alpha().clamp(0., 1.);
+    let mut by_chunk: HashMap<ChunkPo, Vec<(usize, [u8; 4])>> = HashMap::new();
+    for pt in particles.iter() {
+        let (cp, i) = po_to_chunk(&pt.lerp_po(alpha));
+        by_chunk.entry(cp).or_default().push((i, pt.cell.color));
+    }
+    let mut redraw = cells_map.take_dirty();
+    redraw.extend(std::mem::take(&mut sprites.particle_chunks));
+    redraw.extend(by_chunk.keys().copied());
+    for cp in redraw {
+        let chunk = cells_map.get_chunk(&cp).filter(|c| !c.is_empty());
+        let drawn = by_chunk.get(&cp).map_or(&[][..], |v| &v[..]);
+        if chunk.is_none() && drawn.is_empty() {
+            // 区块被释放或者空了，sprite也删掉
+            if let Some((e, handle)) = sprites.map.remove(&cp) {
+                cmds.entity(e).despawn();
+                images.remove(handle.id());
+            }
+            continue;
+        }
+        let (_, handle) = sprites.map.entry(cp).or_insert_with(|| {
+            let handle = images.add(create_chunk_image());
+            let center = chunk_center(&cp);
+            let e = cmds.spawn(SpriteBundle {
+                sprite: Sprite {
+                    custom_size: Some(Vec2::splat((CHUNK_SIZE * PIXEL_SIZE) as f32)),
+                    ..default()
+                },
+                texture: handle.clone(),
+                transform: Transform::from_xyz(center.x, center.y, CHUNK_Z),
+                ..default()
+            }).id();
+            (e, handle)
+        });
+        if let Some(img) = images.get_mut(handle.id()) {
+            write_chunk_image(img, chunk, drawn);
+        }
+    }
+    sprites.particle_chunks = by_chunk.into_keys().collect();
+}
@@ -1,20 +1,33 @@
 use bevy::prelude::*;
-use bevy::utils::hashbrown::HashMap;
+use bevy::render::render_resource::{Extent3d, TextureDimension, TextureFormat};
+use bevy::render::texture::ImageSampler;
+use bevy::utils::hashbrown::{HashMap, HashSet};
 use bevy_rapier2d::prelude::*;
 use rand::seq::IteratorRandom;
 use marching_squares::{Field as MarchingSquaresField, march, simplify};
 use earcutr;
 
-use crate::comm::{Po, PoCreate, PoDir, PIXEL_SIZE, PIXEL_SIZE_F, PIXEL_SIZE_HALF_F, get_fix_pos};
+use crate::comm::{Po, PoCreate, PoDir, PIXEL_SIZE, PIXEL_SIZE_F, PIXEL_SIZE_HALF_F, DEBRIS_Z, nearest_po};
 use crate::components::RigidCheckField;
-use crate::res::CellsMap;
+use crate::res::{CellsMap, MaterialFlag, MaterialRegistry, MoveClass, Particles, SimRng, SimTick, RIGID_SALT};
 use crate::components::*;
 
+const WAKE_VELOCITY: f64 = 1.;
+// 被撞飞的可移动格子变成粒子时的速度，格子/帧
+const IMPACT_EJECT_SPEED: f32 = 2.;
+// 碎片刚体的速度(像素/秒)和角速度低于这些值就算停下了
+const DEBRIS_REST_LINVEL: f32 = 5.;
+const DEBRIS_REST_ANGVEL: f32 = 0.5;
+// 碎片变回格子时找空位的最大距离(格子数)
+const DEBRIS_SNAP_REACH: i32 = 3;
+// 刚生成的刚体速度也是0，碎片和像素物体都要连续静止这么多帧才算停下
+const REST_FRAMES: u16 = 30;
+
 fn evaluate_velocity(x: f64, y: f64) -> f64 {
     (x.powi(2) + y.powi(2)).sqrt()
 }
 
-fn cell_trans_rigid(cmds: &mut Commands, e: Entity, dir: PoDir) {
+fn cell_trans_rigid(cmds: &mut Commands, map: &mut CellsMap, p: Po, dir: PoDir) {
     if let Some(v) = match dir {
         PoDir::Left => {
             Some(Velocity::linear(Vec2 {x: -100., y: 100.}))
@@ -26,36 +39,164 @@ fn cell_trans_rigid(cmds: &mut Commands, e: Entity, dir: PoDir) {
             None
         }
     } {
-        cmds.entity(e).remove::<Cell>().insert((
-            RigidBody::Dynamic,
-            Collider::cuboid(PIXEL_SIZE_HALF_F, PIXEL_SIZE_HALF_F),
-            v
-        ));
+        if let Some(c) = map.del(&p) {
+            cmds.spawn((
+                SpriteBundle {
+                    sprite: Sprite {
+                        custom_size: Some(Vec2::splat(PIXEL_SIZE_F)),
+                        color: c.color(),
+                        ..default()
+                    },
+                    transform: Transform::from_xyz(p.x as f32, p.y as f32, DEBRIS_Z),
+                    ..default()
+                },
+                RigidBody::Dynamic,
+                Collider::cuboid(PIXEL_SIZE_HALF_F, PIXEL_SIZE_HALF_F),
+                v,
+                CellDebris::new(c),
+            ));
+        }
     }
 }
 
 pub fn handle(
     mut query: Query<(&Transform, &mut RigidCheckField, &Velocity)>,
-    map: Res<CellsMap>,
+    mut map: ResMut<CellsMap>,
     mut cmds: Commands,
+    sim_rng: Res<SimRng>,
+    tick: Res<SimTick>,
+    reg: Res<MaterialRegistry>,
 ) {
     for (t, mut r, v) in query.iter_mut() {
         let ev = evaluate_velocity(v.linvel.x as f64, v.linvel.y as f64);
+        let x = t.translation.x as i32;
+        let y = t.translation.y as i32;
+        r.set_xy(x, y);
+        // 刚体碰到的格子要从休眠中唤醒，粉末被撞松重新开始滑动
+        if ev > WAKE_VELOCITY {
+            for p in r.into_iter() {
+                map.wake(&p);
+                if let Some(c) = map.get_mut_quiet(&p) {
+                    if reg.get(c.mat).class == MoveClass::Powder {
+                        c.sliding = true;
+                    }
+                }
+            }
+        }
         if ev > 600. {
             // TODO: 并行化和减少遍历个数;
-            let x = t.translation.x as i32;
-            let y = t.translation.y as i32;
-            r.set_xy(x, y);
             // info!("set {} {}", t.translation.x, t.translation.y);
-            let mut rng = rand::thread_rng();
+            let mut rng = sim_rng.cell_rng_salted(tick.0, &Po {x: x, y: y}, RIGID_SALT);
             for p in r.into_iter().choose_multiple(&mut rng, 10) {
-                if let Some(e) = map.get(&p) {
-                    // println!("{}", p);
-                    let dir = p.calc_dir_lr(&Po {x: x, y: y});
-                    cell_trans_rigid(&mut cmds, *e, dir);
+                // 不可破坏的材质不会被撞飞
+                let Some(m) = map.get(&p).map(|c| reg.get(c.mat)) else {
+                    continue;
+                };
+                if m.has_flag(MaterialFlag::Indestructible) {
+                    continue;
+                }
+                // println!("{}", p);
+                let dir = p.calc_dir_lr(&Po {x: x, y: y});
+                let sx = match dir {
+                    PoDir::Left => -1.,
+                    PoDir::Right => 1.,
+                    _ => 0.,
+                };
+                let v = Vec2::new(sx, 1.) * IMPACT_EJECT_SPEED;
+                // 粉末和液体飞出去变成粒子，气体留在网格里被吹开，其余的还是用刚体
+                match m.class {
+                    MoveClass::Powder | MoveClass::Liquid => {
+                        map.eject(&p, v);
+                    }
+                    MoveClass::Gas => {
+                        if let Some(c) = map.get_mut_quiet(&p) {
+                            c.v = CellVelocity(c.v.0 + v.x, c.v.1 + v.y);
+                        }
+                        map.wake(&p);
+                    }
+                    _ => {
+                        cell_trans_rigid(&mut cmds, &mut map, p, dir);
+                    }
+                }
+            }
+        }
+    }
+}
+
+// 这一帧休眠或足够慢就累加静止帧数，否则清零
+fn count_rest(rest: u16, v: &Velocity, sleeping: Option<&Sleeping>) -> u16 {
+    let slow = sleeping.is_some_and(|s| s.sleeping)
+        || (v.linvel.length() < DEBRIS_REST_LINVEL && v.angvel.abs() < DEBRIS_REST_ANGVEL);
+    if slow { rest.saturating_add(1) } else { 0 }
+}
+
+// 停下来的碎片刚体放回最近的空格子，恢复原来的材质和颜色
+pub fn settle_debris(
+    mut query: Query<(Entity, &Transform, &Velocity, Option<&Sleeping>, &mut CellDebris)>,
+    mut map: ResMut<CellsMap>,
+    mut cmds: Commands,
+) {
+    for (e, t, v, sleeping, mut debris) in query.iter_mut() {
+        debris.rest = count_rest(debris.rest, v, sleeping);
+        if debris.rest < REST_FRAMES {
+            continue;
+        }
+        // 周围都被占满就先留着，等下一帧再试
+        let Some(p) = nearest_free(&map, nearest_po(t.translation.truncate())) else {
+            continue;
+        };
+        map.add(&p, rest_cell(debris.cell));
+        cmds.entity(e).despawn();
+    }
+}
+
+// center附近DEBRIS_SNAP_REACH格以内最近的空位
+fn nearest_free(map: &CellsMap, center: Po) -> Option<Po> {
+    let r = DEBRIS_SNAP_REACH;
+    (-r..=r)
+        .flat_map(|dy| (-r..=r).map(move |dx| Po::new(dx, dy)))
+        .filter(|d| map.get(&(center + *d * PIXEL_SIZE)).is_none())
+        .min_by_key(|d| d.length_squared())
+        .map(|d| center + d * PIXEL_SIZE)
+}
+
+// 放回网格的格子从静止开始
+fn rest_cell(mut c: CellData) -> CellData {
+    c.v = CellVelocity(0., 0.);
+    c.idle = 0;
+    c.sliding = true;
+    c
+}
+
+type PixelObjectItem<'a> = (Entity, &'a Transform, &'a Velocity, Option<&'a Sleeping>, &'a mut PixelObject, &'a Handle<Image>);
+
+// 停下来的刚体把格子按旋转后的位置放回最近的空格子，然后删掉刚体和贴图
+// 附近没有空位的格子变成粒子，落地再回到网格
+pub fn settle_pixel_objects(
+    mut query: Query<PixelObjectItem>,
+    mut map: ResMut<CellsMap>,
+    mut particles: ResMut<Particles>,
+    mut images: ResMut<Assets<Image>>,
+    mut cmds: Commands,
+) {
+    for (e, t, v, sleeping, mut obj, texture) in query.iter_mut() {
+        obj.rest = count_rest(obj.rest, v, sleeping);
+        if obj.rest < REST_FRAMES {
+            continue;
+        }
+        for (local, c) in &obj.cells {
+            let p = nearest_po(t.transform_point(local.extend(0.)).truncate());
+            match nearest_free(&map, p) {
+                Some(p) => {
+                    map.add(&p, rest_cell(*c));
+                }
+                None => {
+                    particles.spawn(&p, Vec2::ZERO, rest_cell(*c));
                 }
             }
         }
+        images.remove(texture.id());
+        cmds.entity(e).despawn();
     }
 }
 
@@ -93,69 +234,167 @@ impl MarchingSquaresField for RigidField {
 
 #[derive(Event)]
 pub struct RigidizeEvent {
-    w: i32,
-    h: i32,
     meterial_value: f32,
 }
 
 impl RigidizeEvent {
-    pub fn new(w: i32, h: i32, meterial_value: f32) -> Self {
+    pub fn new(meterial_value: f32) -> Self {
         Self {
-            w, h, meterial_value
+            meterial_value
         }
     }
 }
 
+const GROUP_NEIGHBORS: [Po; 4] = [Po::new(PIXEL_SIZE, 0), Po::new(-PIXEL_SIZE, 0), Po::new(0, PIXEL_SIZE), Po::new(0, -PIXEL_SIZE)];
+
+// 上下左右相连的格子分成一组
+fn connected_groups(cells: &HashSet<Po>) -> Vec<Vec<Po>> {
+    let mut visited = HashSet::new();
+    let mut groups = Vec::new();
+    for p in cells {
+        if !visited.insert(*p) {
+            continue;
+        }
+        let mut group = vec![*p];
+        let mut i = 0;
+        while i < group.len() {
+            let q = group[i];
+            for d in GROUP_NEIGHBORS {
+                let n = q + d;
+                if cells.contains(&n) && visited.insert(n) {
+                    group.push(n);
+                }
+            }
+            i += 1;
+        }
+        groups.push(group);
+    }
+    groups
+}
+
+// 一组格子的包围盒，单位是格子
+fn group_bounds(group: &[Po]) -> (Po, Po) {
+    group.iter()
+        .map(|p| *p / PIXEL_SIZE)
+        .fold((Po::MAX, Po::MIN), |(min, max), p| (min.min(p), max.max(p)))
+}
+
+// 用一组格子自己的包围盒生成刚体，返回刚体中心和相对中心的三角网格
+fn group_collider(group: &[Po]) -> Option<(Vec2, Vec<Vect>, Vec<[u32; 3]>)> {
+    let cells: Vec<Po> = group.iter().map(|p| *p / PIXEL_SIZE).collect();
+    let (min, max) = group_bounds(group);
+    // 四周留一格空白，轮廓才是闭合的
+    let origin = min - Po::ONE;
+    let size = max - min + Po::splat(3);
+    let mut rigid_field = RigidField::new(size.x as usize, size.y as usize);
+    for p in &cells {
+        rigid_field.set_field(*p - origin, 1.);
+    }
+    let center = (min + max).as_vec2() / 2.;
+
+    let mut coords = Vec::new();
+    let mut indices = Vec::new();
+    let contours: Vec<Vec<(f64, f64)>> = march(&rigid_field, 0.5);
+    for c in contours {
+        let v = simplify::simplify_with_eps(&c, 10.);
+        let mut verticles = Vec::new();
+        for (x, y) in &v {
+            verticles.push(*x as f32);
+            verticles.push(*y as f32);
+        }
+
+        let Ok(result) = earcutr::earcut(&verticles, &[], 2) else {
+            continue;
+        };
+        let local = |i: usize| Vect::new(
+            (verticles[i*2] + origin.x as f32 - center.x) * PIXEL_SIZE_F,
+            (verticles[i*2+1] + origin.y as f32 - center.y) * PIXEL_SIZE_F,
+        );
+        for t in result.chunks(3) {
+            let n = coords.len() as u32;
+            coords.extend([local(t[0]), local(t[1]), local(t[2])]);
+            indices.push([n, n + 1, n + 2]);
+        }
+    }
+    if coords.is_empty() {
+        return None;
+    }
+    Some((center * PIXEL_SIZE_F, coords, indices))
+}
+
+// 刚体的贴图，每个格子一个像素，第0行在上面
+fn pixel_object_image(cells: &[(Po, CellData)], min: Po, max: Po) -> Image {
+    let size = max - min + Po::ONE;
+    let mut img = Image::new_fill(
+        Extent3d {
+            width: size.x as u32,
+            height: size.y as u32,
+            depth_or_array_layers: 1,
+        },
+        TextureDimension::D2,
+        &[0, 0, 0, 0],
+        TextureFormat::Rgba8UnormSrgb,
+    );
+    img.sampler = ImageSampler::nearest();
+    for (p, c) in cells {
+        let x = (p.x / PIXEL_SIZE - min.x) as usize;
+        let row = (max.y - p.y / PIXEL_SIZE) as usize;
+        let offset = (row * size.x as usize + x) * 4;
+        img.data[offset..offset + 4].copy_from_slice(&c.color);
+    }
+    img
+}
+
+// 带同一个刚体标记、互相连着的格子各自生成一个刚体，生成后去掉标记
+// 格子从CellsMap里拿出来交给刚体，用刚体的Transform画，跟着一起移动和旋转，停下后见settle_pixel_objects
 pub fn rigidize(
-    query: Query<(&Transform, &RigidMeterial)>,
+    mut map: ResMut<CellsMap>,
     mut event: EventReader<RigidizeEvent>,
-    mut gizmos: Gizmos,
     mut cmds: Commands,
+    mut images: ResMut<Assets<Image>>,
 ) {
-    // let w = &1920;
-    // let h = &1980;
-    // let meterial_value = &1.;
-    for RigidizeEvent {w, h, meterial_value} in event.read() {
-        let w = *w;
-        let h = *h;
-        let w_half = w / 2;
-        let h_half = h / 2;
-        let mut rigid_field = RigidField::new(w as usize, h as usize);
-
-        for (t, c) in query.iter() {
-            let x = get_fix_pos(t.translation.x as i32) / PIXEL_SIZE;
-            let y = get_fix_pos(t.translation.y as i32) / PIXEL_SIZE;
-            gizmos.cuboid(Transform::from_xyz(x as f32, y as f32, 0.), Color::GREEN);
-            if *meterial_value == c.0 && x.abs() < w_half && y.abs() < h_half {
-                rigid_field.set_field(Po {x: x + w_half, y: y + h_half}, c.0 as f64);
-            }
-        }
-        
-        let contours: Vec<Vec<(f64, f64)>> = march(&rigid_field, 0.5);
-        for c in contours {
-            let v = simplify::simplify_with_eps(&c, 10.);
-            let mut verticles = Vec::new();
-            for (x, y) in &v {
-                verticles.push(*x as f32);
-                verticles.push(*y as f32);
-            } 
-    
-            let result = earcutr::earcut(&verticles, &[], 2).unwrap();
-            let mut coords = Vec::new();
-            let mut indices = Vec::new();
-            for (i, t) in result.chunks(3).enumerate() {
-                coords.push(Vect::new((verticles[t[0]*2] as f32 - w_half as f32) * PIXEL_SIZE_F, (verticles[t[0]*2+1] as f32 - h_half as f32)*PIXEL_SIZE_F));
-                coords.push(Vect::new((verticles[t[1]*2] as f32 - w_half as f32) * PIXEL_SIZE_F, (verticles[t[1]*2+1] as f32 - h_half as f32)*PIXEL_SIZE_F));
-                coords.push(Vect::new((verticles[t[2]*2] as f32 - w_half as f32) * PIXEL_SIZE_F, (verticles[t[2]*2+1] as f32 - h_half as f32)*PIXEL_SIZE_F));
-                indices.push([(i*3) as u32, (i*3+1) as u32, (i*3+2) as u32])
-            }
-
-            if !coords.is_empty() {
-                cmds.spawn((
-                    RigidBody::Dynamic,
-                    Collider::trimesh(coords, indices),
-                ));
-            }
+    for RigidizeEvent {meterial_value} in event.read() {
+        let cells: HashSet<Po> = map.iter()
+            .filter(|(_, c)| c.rm.is_some_and(|rm| rm.0 == *meterial_value))
+            .map(|(p, _)| p)
+            .collect();
+        for group in connected_groups(&cells) {
+            let Some((center, coords, indices)) = group_collider(&group) else {
+                for p in &group {
+                    if let Some(c) = map.get_mut(p) {
+                        c.rm = None;
+                    }
+                }
+                continue;
+            };
+            let cells: Vec<(Po, CellData)> = group.iter()
+                .filter_map(|p| map.del(p).map(|mut c| {
+                    c.rm = None;
+                    (*p, c)
+                }))
+                .collect();
+            let (min, max) = group_bounds(&group);
+            let size = (max - min + Po::ONE) * PIXEL_SIZE;
+            let texture = images.add(pixel_object_image(&cells, min, max));
+            cmds.spawn((
+                SpriteBundle {
+                    sprite: Sprite {
+                        custom_size: Some(size.as_vec2()),
+                        ..default()
+                    },
+                    texture,
+                    transform: Transform::from_xyz(center.x, center.y, DEBRIS_Z),
+                    ..default()
+                },
+                RigidBody::Dynamic,
+                Collider::trimesh(coords, indices),
+                Velocity::zero(),
+                RigidCheckField::new(size.x, size.y),
+                PixelObject {
+                    cells: cells.into_iter().map(|(p, c)| (p.as_vec2() - center, c)).collect(),
+                    rest: 0,
+                },
+            ));
         }
     }
-}
\ No newline at end of file
+}
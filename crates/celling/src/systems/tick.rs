@@ -0,0 +1,18 @@
+use bevy::prelude::*;
+
+use crate::res::*;
+
+// 按累计的时间跑若干次SimSchedule
+pub fn run_sim_schedule(world: &mut World) {
+    let delta = world.resource::<Time>().delta();
+    let n = world.resource_mut::<SimTime>().accumulate(delta);
+    for _ in 0..n {
+        world.run_schedule(SimSchedule);
+    }
+}
+
+pub fn advance_tick(
+    mut tick: ResMut<SimTick>,
+) {
+    tick.0 = tick.0.wrapping_add(1);
+}